@@ -0,0 +1,121 @@
+use crate::{config, metrics, AccessLogFormat};
+use std::time::Instant;
+use tide::{Middleware, Next, Request};
+
+/// Logs every request to standard output and records it in the metrics.
+pub(crate) struct AccessLog;
+
+/// Find out which route a path (without the base path) belongs to, and the
+/// name of the repository if it is in one.
+///
+/// This is only used to group requests in the metrics and logs, so it does not
+/// have to agree with the router on paths that do not exist.
+fn route(path: &str) -> (&'static str, Option<String>) {
+  let mut segments = path.trim_start_matches('/').split('/');
+  let first = segments.next().unwrap_or_default();
+  let route = match first {
+    "" => return ("index", None),
+    "activity" => return ("activity", None),
+    "feed.xml" => return ("activity_feed", None),
+    "static" => return ("static", None),
+    "metrics" => return ("metrics", None),
+    "healthz" => return ("healthz", None),
+    "readyz" => return ("readyz", None),
+    "api" => return ("api", None),
+    _ => match (segments.next(), segments.next()) {
+      (None | Some(""), _) => "repo_home",
+      (Some("info"), _) => "git_refs",
+      (Some("HEAD" | "objects"), _) => "git_data",
+      (Some("commit"), _) if path.ends_with("/raw") => "commit_raw",
+      (Some("commit"), _) => "commit",
+      (Some("archive"), _) => "archive",
+      (Some("refs"), None | Some("")) => "refs",
+      (Some("refs"), _) => "tag",
+      (Some("refs.xml" | "refs.atom"), _) => "refs_feed",
+      (Some("log.xml" | "log.atom"), _) => "log_feed",
+      (Some("log"), _) if path.ends_with("/feed.xml") || path.ends_with("/feed.atom") => "log_feed",
+      (Some("log"), _) => "log",
+      (Some("lists"), None | Some("")) => "lists",
+      (Some("lists"), _) => "thread",
+      (Some("patches"), None | Some("")) => "patches",
+      (Some("patches"), _) => "series",
+      (Some("webhooks"), _) => "webhooks",
+      (Some("tree"), _) if segments.next() == Some("raw") => "tree_raw",
+      (Some("tree"), _) => "tree",
+      _ => "other",
+    },
+  };
+
+  let repo = percent_encoding::percent_decode_str(first)
+    .decode_utf8_lossy()
+    .into_owned();
+  (route, Some(repo))
+}
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AccessLog {
+  async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+    let start = Instant::now();
+    // only the address, not the port of the peer
+    let remote = match req.remote() {
+      Some(remote) => match remote.parse::<std::net::SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => remote.to_string(),
+      },
+      None => "-".to_string(),
+    };
+    let method = req.method();
+    let path = req.url().path().to_string();
+    let target = match req.url().query() {
+      Some(query) => format!("{}?{}", path, query),
+      None => path.clone(),
+    };
+    let version = req
+      .version()
+      .map_or("HTTP/1.1".to_string(), |v| v.to_string());
+
+    let response = next.run(req).await;
+
+    // streamed responses are still being sent at this point
+    let latency = start.elapsed();
+    let status = response.status();
+    let bytes = response.len();
+
+    let config = config();
+    let (route, repo) = route(path.strip_prefix(&config.base_path).unwrap_or(&path));
+    metrics::record(route, status.into(), latency);
+    if route == "git_refs" && status.is_success() {
+      metrics::record_clone(repo.as_deref().unwrap_or_default());
+    }
+
+    match config.access_log {
+      AccessLogFormat::Off => {}
+      AccessLogFormat::Common => println!(
+        "{} - - [{}] \"{} {} {}\" {} {}",
+        remote,
+        chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+        method,
+        target,
+        version,
+        u16::from(status),
+        bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+      ),
+      AccessLogFormat::Json => println!(
+        "{}",
+        serde_json::json!({
+          "time": chrono::Local::now().to_rfc3339(),
+          "remote": remote,
+          "method": method.to_string(),
+          "path": target,
+          "route": route,
+          "repo": repo,
+          "status": u16::from(status),
+          "bytes": bytes,
+          "latency_ms": latency.as_secs_f64() * 1000.0,
+        })
+      ),
+    }
+
+    Ok(response)
+  }
+}
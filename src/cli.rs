@@ -0,0 +1,281 @@
+use crate::config;
+use git2::Repository;
+use std::{
+  ffi::OsString,
+  fs,
+  io::Write,
+  path::{Path, PathBuf},
+};
+
+const HELP: &str = "
+Usage: agit [OPTIONS] [COMMAND]
+
+COMMANDS:
+  serve                 Run the web server. This is the default.
+  check                 Check the configuration and list which repositories
+                        are exported, and why the others are not.
+  init <NAME>           Create a new exported bare repository.
+  render <PATH>         Print the page at PATH, for example /agit/log, to
+                        standard output.
+  export <OUTDIR>       Write the whole site as static files to OUTDIR.
+
+FLAGS:
+  -h, --help            Prints this help information and exits.
+  --print-config        Prints the configuration that is used after applying
+                        all overrides and exits.
+OPTIONS:
+  -c, --config <FILE>   Use a specific configuration file.
+                        default is $AGIT_CONFIG or ./agit.toml
+  --set <KEY=VALUE>     Set the configuration option KEY. Can be given
+                        multiple times.
+  -p, --port <PORT>     Listen on this port instead of the configured one.
+  -r, --repos-root <DIR>
+                        Find repositories in this directory instead of the
+                        configured one.
+  -d, --description <TEXT>
+                        Description of the repository created by init.
+  --mirror <URL>        Make the repository created by init a mirror of the
+                        repository at URL.
+  --ref <REF>           Export the log and tree of this ref. Can be given
+                        multiple times, default is the branch HEAD points to.
+  -f, --force           Export all commits again, even if they were already
+                        exported before.
+
+CONFIGURATION:
+  Every option of the configuration file can also be set with an environment
+  variable named like the option in upper case with an AGIT_ prefix, for
+  example AGIT_SITE_NAME. Environment variables override the configuration
+  file, and the command line overrides both.
+";
+
+pub(crate) enum Command {
+  Serve,
+  Check,
+  PrintConfig,
+  Init {
+    name: String,
+    description: Option<String>,
+    mirror: Option<String>,
+  },
+  Render {
+    path: String,
+  },
+  Export {
+    outdir: PathBuf,
+    refs: Vec<String>,
+    force: bool,
+  },
+}
+
+/// Parsed command line arguments
+pub(crate) struct Args {
+  pub(crate) command: Command,
+  pub(crate) config_filename: String,
+  pub(crate) port: Option<u16>,
+  pub(crate) repos_root: Option<String>,
+  /// configuration options set with `--set`, in the order they were given
+  pub(crate) overrides: Vec<(String, String)>,
+}
+
+fn usage_error(message: &str) -> ! {
+  eprintln!("error: {message}");
+  eprint!("{HELP}");
+  std::process::exit(2);
+}
+
+pub(crate) fn parse() -> Args {
+  let mut pargs = pico_args::Arguments::from_env();
+
+  if pargs.contains(["-h", "--help"]) {
+    print!("{HELP}");
+    std::process::exit(0);
+  }
+
+  let config_filename = pargs
+    .opt_value_from_str(["-c", "--config"])
+    .unwrap_or_else(|e| usage_error(&e.to_string()))
+    .or_else(|| std::env::var("AGIT_CONFIG").ok())
+    .unwrap_or_else(|| "agit.toml".to_string());
+  let port = pargs
+    .opt_value_from_str(["-p", "--port"])
+    .unwrap_or_else(|e| usage_error(&e.to_string()));
+  let repos_root = pargs
+    .opt_value_from_str(["-r", "--repos-root"])
+    .unwrap_or_else(|e| usage_error(&e.to_string()));
+  let overrides = pargs
+    .values_from_fn("--set", |option| {
+      option
+        .split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or("has to be of the form KEY=VALUE")
+    })
+    .unwrap_or_else(|e| usage_error(&e.to_string()));
+  let print_config = pargs.contains("--print-config");
+  let description = pargs
+    .opt_value_from_str(["-d", "--description"])
+    .unwrap_or_else(|e| usage_error(&e.to_string()));
+  let mirror = pargs
+    .opt_value_from_str("--mirror")
+    .unwrap_or_else(|e| usage_error(&e.to_string()));
+  let refs = pargs
+    .values_from_str("--ref")
+    .unwrap_or_else(|e| usage_error(&e.to_string()));
+  let force = pargs.contains(["-f", "--force"]);
+
+  let mut free = pargs
+    .finish()
+    .into_iter()
+    .map(OsString::into_string)
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap_or_else(|_| usage_error("arguments have to be valid UTF-8"))
+    .into_iter();
+  let command = match free.next().as_deref() {
+    None | Some("serve") => Command::Serve,
+    Some("check") => Command::Check,
+    Some("init") => Command::Init {
+      name: free
+        .next()
+        .unwrap_or_else(|| usage_error("init needs the name of the repository")),
+      description,
+      mirror,
+    },
+    Some("render") => Command::Render {
+      path: free
+        .next()
+        .unwrap_or_else(|| usage_error("render needs the path of a page")),
+    },
+    Some("export") => Command::Export {
+      outdir: free
+        .next()
+        .unwrap_or_else(|| usage_error("export needs an output directory"))
+        .into(),
+      refs,
+      force,
+    },
+    Some(other) => usage_error(&format!("unknown command {other:?}")),
+  };
+  let command = match command {
+    Command::Serve if print_config => Command::PrintConfig,
+    _ if print_config => usage_error("--print-config can't be used with a command"),
+    command => command,
+  };
+  if let Some(extra) = free.next() {
+    usage_error(&format!("unexpected argument {extra:?}"));
+  }
+
+  Args {
+    command,
+    config_filename,
+    port,
+    repos_root,
+    overrides,
+  }
+}
+
+/// Check the configuration and explain which repositories are exported.
+pub(crate) fn check() -> Result<(), std::io::Error> {
+  // loading the configuration exits if it is invalid
+  let config = config();
+  println!(
+    "configuration ok, repositories root is {:?}",
+    config.repos_root
+  );
+
+  let mut entries = fs::read_dir(&config.repos_root)?
+    .filter_map(|entry| Some(entry.ok()?.path()))
+    .collect::<Vec<_>>();
+  entries.sort();
+
+  for path in entries {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let status = match Repository::open(&path) {
+      Err(_) if !path.is_dir() => "not exported: not a directory".to_string(),
+      Err(e) => format!("not exported: not a git repository ({})", e.message()),
+      Ok(repo) if !repo.path().join(&config.export_ok).exists() => {
+        format!("not exported: there is no {:?} file", config.export_ok)
+      }
+      Ok(repo) if repo.is_empty().unwrap_or(false) => "exported, but empty".to_string(),
+      Ok(repo) => match repo.head().and_then(|head| head.peel_to_commit()) {
+        Ok(_) => "exported".to_string(),
+        Err(e) => format!("exported, but HEAD is broken ({})", e.message()),
+      },
+    };
+    println!("{name}: {status}");
+  }
+
+  Ok(())
+}
+
+/// Print the configuration in the format of the configuration file.
+pub(crate) fn print_config() -> Result<(), std::io::Error> {
+  let config = toml::to_string(&*config()).map_err(std::io::Error::other)?;
+  print!("{config}");
+  Ok(())
+}
+
+/// Create a new bare repository that is set up for agit.
+pub(crate) fn init(
+  name: &str,
+  description: Option<&str>,
+  mirror: Option<&str>,
+) -> Result<(), std::io::Error> {
+  let config = config();
+  let path = Path::new(&config.repos_root).join(name);
+  if path.exists() {
+    eprintln!("error: {path:?} already exists");
+    std::process::exit(1);
+  }
+
+  let repo = Repository::init_bare(&path).map_err(std::io::Error::other)?;
+  fs::write(repo.path().join(&config.export_ok), "")?;
+  if let Some(description) = description {
+    fs::write(repo.path().join("description"), format!("{description}\n"))?;
+  }
+
+  // update the "dumb http" server info on updates
+  let hook = repo.path().join("hooks").join("post-update");
+  fs::create_dir_all(hook.parent().unwrap())?;
+  fs::write(&hook, "#!/bin/sh\nexec git update-server-info\n")?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+  }
+
+  println!("initialized empty repository in {:?}", repo.path());
+  if let Some(url) = mirror {
+    repo
+      .config()
+      .and_then(|mut config| config.set_str("agit.mirror", url))
+      .map_err(std::io::Error::other)?;
+    let mirror = crate::mirror::mirror(&repo).unwrap();
+    crate::mirror::sync(&repo, &mirror)?;
+    match crate::mirror::mirror(&repo).unwrap().error() {
+      Some(error) => eprintln!("warning: could not fetch {url}: {error}"),
+      None => println!("fetched {url}"),
+    }
+  }
+  if description.is_none() {
+    println!(
+      "you can describe it in {:?}",
+      repo.path().join("description")
+    );
+  }
+  Ok(())
+}
+
+/// Print a single page to standard output, without starting a server.
+pub(crate) async fn render(path: &str) -> Result<(), std::io::Error> {
+  let mut response = crate::export::get(&crate::app(), path).await?;
+  let body = response
+    .body_bytes()
+    .await
+    .map_err(|e| std::io::Error::other(e.into_inner()))?;
+
+  std::io::stdout().write_all(&body)?;
+  if !response.status().is_success() {
+    eprintln!("error: {path} responded with {}", response.status());
+    std::process::exit(1);
+  }
+  Ok(())
+}
@@ -0,0 +1,283 @@
+use crate::{config, filters, StaticDir};
+use git2::{ObjectType, Repository, TreeWalkMode, TreeWalkResult};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::{
+  fs,
+  io::{Error, ErrorKind},
+  path::{Path, PathBuf},
+};
+use tide::http::{Method, Request, Response, Url};
+
+/// Characters that have to be escaped in a path segment of a URL
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+  .add(b' ')
+  .add(b'"')
+  .add(b'#')
+  .add(b'%')
+  .add(b'/')
+  .add(b'<')
+  .add(b'>')
+  .add(b'?')
+  .add(b'`')
+  .add(b'{')
+  .add(b'}');
+
+/// Send a GET request for `path`, relative to the base path, to the app without
+/// going through a server.
+pub(crate) async fn get(app: &tide::Server<()>, path: &str) -> Result<Response, Error> {
+  let config = config();
+  let base = if config.base_url.is_empty() {
+    "http://localhost"
+  } else {
+    &config.base_url
+  };
+  let url = Url::parse(base)
+    .and_then(|base| base.join(&format!("{}{}", config.base_path, path)))
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+  app
+    .respond(Request::new(Method::Get, url))
+    .await
+    .map_err(|e| Error::other(e.into_inner()))
+}
+
+/// How a page is stored in the output directory
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+  /// an HTML page that is stored as `index.html` in a directory of the same
+  /// name, so it can be found by static web servers without the extension
+  Page,
+  /// stored under the same name as in the URL, like feeds and raw files
+  File,
+}
+
+struct Exporter {
+  app: tide::Server<()>,
+  outdir: PathBuf,
+  force: bool,
+  written: usize,
+  skipped: usize,
+  failed: usize,
+}
+
+impl Exporter {
+  /// Render the page at the path made up of `segments` and write it to the
+  /// corresponding file in the output directory. If `immutable` is set and the
+  /// file already exists, the page is not rendered again.
+  async fn export(&mut self, segments: &[&str], kind: Kind, immutable: bool) {
+    let mut url = String::new();
+    let mut file = self.outdir.clone();
+    for segment in segments {
+      url.push('/');
+      url.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+      file.push(segment);
+    }
+    if url.is_empty() {
+      url.push('/');
+    }
+    if kind == Kind::Page {
+      file.push("index.html");
+    }
+
+    if immutable && !self.force && file.exists() {
+      self.skipped += 1;
+      return;
+    }
+    if let Some(conflict) = file
+      .ancestors()
+      .skip(1)
+      .take_while(|dir| *dir != self.outdir)
+      .find(|dir| dir.is_file())
+    {
+      // e.g. a file called index.html in a directory of the repository
+      eprintln!("warning: not exporting {url}, it would replace {conflict:?}");
+      return;
+    }
+
+    if let Err(e) = self.write(&url, &file).await {
+      eprintln!("error: could not export {url}: {e}");
+      self.failed += 1;
+    } else {
+      self.written += 1;
+    }
+  }
+
+  async fn write(&self, url: &str, file: &Path) -> Result<(), Error> {
+    let mut response = get(&self.app, url).await?;
+    let body = if response.status().is_redirection() {
+      // static web servers can't redirect, but browsers can follow this
+      let location = response
+        .header("Location")
+        .map(|location| location.as_str().to_string())
+        .unwrap_or_default();
+      format!("<!DOCTYPE html><meta http-equiv=\"refresh\" content=\"0; url={location}\">")
+        .into_bytes()
+    } else if response.status().is_success() {
+      response
+        .body_bytes()
+        .await
+        .map_err(|e| Error::other(e.into_inner()))?
+    } else {
+      return Err(Error::other(format!("status {}", response.status())));
+    };
+
+    fs::create_dir_all(file.parent().unwrap())?;
+    fs::write(file, body)
+  }
+
+  async fn export_repo(&mut self, repo: &Repository, refs: &[String]) -> Result<(), Error> {
+    let name = filters::repo_name(repo).unwrap().to_string();
+    let name = name.as_str();
+
+    self.export(&[name], Kind::Page, false).await;
+    if repo.is_empty().unwrap_or(true) {
+      return Ok(());
+    }
+
+    self.export(&[name, "refs"], Kind::Page, false).await;
+    self.export(&[name, "refs.xml"], Kind::File, false).await;
+    self.export(&[name, "refs.atom"], Kind::File, false).await;
+    for (link, ..) in crate::routes::tags(repo) {
+      let segments = std::iter::once(name)
+        .chain(link.split('/'))
+        .collect::<Vec<_>>();
+      self.export(&segments, Kind::Page, false).await;
+    }
+
+    self.export(&[name, "log"], Kind::Page, false).await;
+    self.export(&[name, "log.xml"], Kind::File, false).await;
+    self.export(&[name, "log.atom"], Kind::File, false).await;
+    self.export(&[name, "tree"], Kind::Page, false).await;
+
+    let head = repo.head().map_err(Error::other)?;
+    let refs = if refs.is_empty() {
+      vec![head.shorthand().unwrap_or("HEAD").to_string()]
+    } else {
+      refs.to_vec()
+    };
+
+    for r in &refs {
+      let commit = match repo.revparse_single(r).and_then(|obj| obj.peel_to_commit()) {
+        Ok(commit) => commit,
+        Err(e) => {
+          eprintln!("warning: skipping {r} in {name}: {}", e.message());
+          continue;
+        }
+      };
+
+      // the log, one page at a time
+      self.export(&[name, "log", r], Kind::Page, false).await;
+      let per_page = config().log_per_page;
+      let mut n = per_page;
+      while repo.revparse_single(&format!("{r}~{n}")).is_ok() {
+        self
+          .export(&[name, "log", &format!("{r}~{n}")], Kind::Page, false)
+          .await;
+        n += per_page;
+      }
+      self
+        .export(&[name, "log", r, "feed.xml"], Kind::File, false)
+        .await;
+      self
+        .export(&[name, "log", r, "feed.atom"], Kind::File, false)
+        .await;
+
+      // all commits, which never change once they are rendered
+      let mut revwalk = repo.revwalk().map_err(Error::other)?;
+      revwalk.push(commit.id()).map_err(Error::other)?;
+      for oid in revwalk.filter_map(Result::ok) {
+        let id = oid.to_string();
+        self.export(&[name, "commit", &id], Kind::Page, true).await;
+        self
+          .export(&[name, "commit", &id, "raw"], Kind::File, true)
+          .await;
+      }
+
+      // the files in the tree
+      self.export(&[name, "tree", r], Kind::Page, false).await;
+      let mut entries = Vec::new();
+      commit
+        .tree()
+        .map_err(Error::other)?
+        .walk(TreeWalkMode::PreOrder, |root, entry| {
+          if let Some(entry_name) = entry.name() {
+            entries.push((format!("{root}{entry_name}"), entry.kind()));
+          }
+          TreeWalkResult::Ok
+        })
+        .map_err(Error::other)?;
+      for (path, kind) in entries {
+        let path_segments = path.split('/');
+        match kind {
+          Some(ObjectType::Tree) => {
+            let segments = [name, "tree", r, "item"]
+              .into_iter()
+              .chain(path_segments)
+              .collect::<Vec<_>>();
+            self.export(&segments, Kind::Page, false).await;
+          }
+          Some(ObjectType::Blob) => {
+            let segments = [name, "tree", r, "item"]
+              .into_iter()
+              .chain(path_segments.clone())
+              .collect::<Vec<_>>();
+            self.export(&segments, Kind::Page, false).await;
+            let segments = [name, "tree", r, "raw"]
+              .into_iter()
+              .chain(path_segments)
+              .collect::<Vec<_>>();
+            self.export(&segments, Kind::File, false).await;
+          }
+          // submodules can not be shown
+          _ => {}
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Write the site as static files to `outdir`, including all commits and the
+/// trees of `refs`, or of the default branch if no refs are given.
+///
+/// Commit pages that were already exported are not rendered again, unless
+/// `force` is set.
+pub(crate) async fn export(outdir: &Path, refs: &[String], force: bool) -> Result<(), Error> {
+  let mut exporter = Exporter {
+    app: crate::app(),
+    outdir: outdir.to_path_buf(),
+    force,
+    written: 0,
+    skipped: 0,
+    failed: 0,
+  };
+
+  exporter.export(&[], Kind::Page, false).await;
+  exporter.export(&["activity"], Kind::Page, false).await;
+  exporter.export(&["feed.xml"], Kind::File, false).await;
+
+  for repo in crate::exported_repos() {
+    if let Err(e) = exporter.export_repo(&repo, refs).await {
+      eprintln!("error: could not export {:?}: {e}", repo.path());
+      exporter.failed += 1;
+    }
+  }
+
+  for path in StaticDir::iter() {
+    let file = StaticDir::get(&path).unwrap();
+    let dest = outdir.join("static").join(path.as_ref());
+    fs::create_dir_all(dest.parent().unwrap())?;
+    fs::write(dest, file.data)?;
+  }
+
+  println!(
+    "exported {} pages to {:?}, skipped {} unchanged commit pages",
+    exporter.written, outdir, exporter.skipped
+  );
+  if exporter.failed > 0 {
+    eprintln!("{} pages could not be exported", exporter.failed);
+    std::process::exit(1);
+  }
+  Ok(())
+}
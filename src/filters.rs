@@ -3,8 +3,14 @@ use git2::{Commit, Repository, Signature, Time};
 pub(crate) fn format_datetime(time: Time, format: &str) -> askama::Result<String> {
   use chrono::{FixedOffset, TimeZone};
 
-  let offset = FixedOffset::east(time.offset_minutes() * 60);
-  let datetime = offset.timestamp(time.seconds(), 0);
+  // commits can have any offset and time, show them in UTC if chrono can't
+  // represent them
+  let offset = FixedOffset::east_opt(time.offset_minutes() * 60)
+    .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+  let datetime = offset
+    .timestamp_opt(time.seconds(), 0)
+    .single()
+    .ok_or_else(|| askama::Error::Custom("the time is out of range".into()))?;
   Ok(datetime.format(format).to_string())
 }
 
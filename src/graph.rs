@@ -0,0 +1,413 @@
+//! Drawing the branches and merges of the log as lanes next to the commits,
+//! like `git log --graph` does.
+
+use git2::{Commit, Oid};
+use std::{
+  collections::{BTreeSet, HashMap},
+  fmt::Write,
+};
+
+/// The width of a lane in SVG units
+const LANE_WIDTH: usize = 10;
+/// The height of a row in SVG units, which is as high as a line of text
+const ROW_HEIGHT: usize = 20;
+/// The number of colours lanes cycle through, see the `lane-*` classes in the
+/// CSS
+const COLOURS: usize = 6;
+
+/// The part of the graph in the row of one commit.
+pub(crate) struct Row {
+  /// the lane of the commit
+  column: usize,
+  colour: usize,
+  lines: Vec<Line>,
+}
+
+/// A line of a lane within a row.
+struct Line {
+  from: Point,
+  to: Point,
+  colour: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Point {
+  /// a lane at the top of the row
+  Top(usize),
+  /// the dot of the commit
+  Commit,
+  /// a lane at the bottom of the row
+  Bottom(usize),
+}
+
+/// A line of history that continues in the following rows.
+struct Lane {
+  /// the commit the lane leads to
+  commit: Oid,
+  colour: usize,
+}
+
+/// Sort `commits` so that every commit comes after all of its children, and
+/// otherwise stays where it was, like `git log --date-order` does for commits
+/// sorted by time.
+///
+/// libgit2 only sorts topologically after walking the whole history, which
+/// takes too long for large repositories.
+pub(crate) fn sort(commits: Vec<Commit>) -> Vec<Commit> {
+  let index = commits
+    .iter()
+    .enumerate()
+    .map(|(i, commit)| (commit.id(), i))
+    .collect::<HashMap<_, _>>();
+  let parents = |commit: &Commit| {
+    commit
+      .parent_ids()
+      .filter_map(|parent| index.get(&parent).copied())
+      .collect::<Vec<_>>()
+  };
+  // the number of children of every commit that are not sorted yet
+  let mut children = vec![0; commits.len()];
+  for commit in &commits {
+    for parent in parents(commit) {
+      children[parent] += 1;
+    }
+  }
+
+  let mut ready = (0..commits.len())
+    .filter(|&i| children[i] == 0)
+    .collect::<BTreeSet<_>>();
+  let mut order = Vec::with_capacity(commits.len());
+  while let Some(i) = ready.pop_first() {
+    order.push(i);
+    for parent in parents(&commits[i]) {
+      children[parent] -= 1;
+      if children[parent] == 0 {
+        ready.insert(parent);
+      }
+    }
+  }
+
+  let mut commits = commits.into_iter().map(Some).collect::<Vec<_>>();
+  order
+    .into_iter()
+    .map(|i| commits[i].take().unwrap())
+    .collect()
+}
+
+/// Lay out the lanes of `commits`, which have to be sorted topologically.
+pub(crate) fn rows(commits: &[Commit]) -> Vec<Row> {
+  // `None` only while the lanes of a row are being rearranged
+  let mut lanes: Vec<Option<Lane>> = Vec::new();
+  let mut colours = 0..;
+  commits
+    .iter()
+    .map(|commit| {
+      let id = commit.id();
+      let mut lines = Vec::new();
+      let mut through = Vec::new();
+      let mut incoming = None;
+      for (i, lane) in lanes.iter().enumerate() {
+        let lane = lane.as_ref().unwrap();
+        if lane.commit == id {
+          incoming.get_or_insert(i);
+          lines.push(Line {
+            from: Point::Top(i),
+            to: Point::Commit,
+            colour: lane.colour,
+          });
+        } else {
+          through.push(i);
+        }
+      }
+      for lane in &mut lanes {
+        if lane.as_ref().is_some_and(|lane| lane.commit == id) {
+          *lane = None;
+        }
+      }
+      // a commit that no lane leads to starts a new one, like a branch tip
+      let column = incoming.unwrap_or_else(|| {
+        lanes.push(None);
+        lanes.len() - 1
+      });
+      let colour = match lines.first() {
+        Some(line) => line.colour,
+        None => colours.next().unwrap(),
+      };
+
+      let mut outgoing = Vec::new();
+      for (n, parent) in commit.parent_ids().enumerate() {
+        // a parent that already has a lane is joined there
+        let existing = lanes
+          .iter()
+          .position(|lane| lane.as_ref().is_some_and(|lane| lane.commit == parent));
+        let i = match existing {
+          Some(i) => i,
+          // the first parent continues the lane of the commit
+          None if n == 0 => {
+            lanes[column] = Some(Lane {
+              commit: parent,
+              colour,
+            });
+            column
+          }
+          None => {
+            lanes.push(Some(Lane {
+              commit: parent,
+              colour: colours.next().unwrap(),
+            }));
+            lanes.len() - 1
+          }
+        };
+        outgoing.push(i);
+      }
+
+      // close the gaps of lanes that ended, so the graph stays narrow
+      let mut moved = Vec::with_capacity(lanes.len());
+      let mut next = 0;
+      for lane in &lanes {
+        moved.push(next);
+        next += lane.is_some() as usize;
+      }
+      for i in through {
+        lines.push(Line {
+          from: Point::Top(i),
+          to: Point::Bottom(moved[i]),
+          colour: lanes[i].as_ref().unwrap().colour,
+        });
+      }
+      for i in outgoing {
+        lines.push(Line {
+          from: Point::Commit,
+          to: Point::Bottom(moved[i]),
+          colour: lanes[i].as_ref().unwrap().colour,
+        });
+      }
+      lanes.retain(Option::is_some);
+
+      Row {
+        column,
+        colour,
+        lines,
+      }
+    })
+    .collect()
+}
+
+impl Row {
+  /// Draw the row as an SVG image.
+  pub(crate) fn svg(&self) -> String {
+    let width = self
+      .lines
+      .iter()
+      .flat_map(|line| [line.from, line.to])
+      .fold(self.column, |max, point| match point {
+        Point::Top(lane) | Point::Bottom(lane) => max.max(lane),
+        Point::Commit => max,
+      })
+      + 1;
+    let x = |lane: usize| lane * LANE_WIDTH + LANE_WIDTH / 2;
+    let position = |point| match point {
+      Point::Top(lane) => (x(lane), 0),
+      Point::Commit => (x(self.column), ROW_HEIGHT / 2),
+      Point::Bottom(lane) => (x(lane), ROW_HEIGHT),
+    };
+
+    // the height of a row is 1.5em, as high as a line of text
+    let mut svg = format!(
+      r#"<svg width="{}em" height="1.5em" viewBox="0 0 {} {ROW_HEIGHT}">"#,
+      (width * LANE_WIDTH) as f32 * 1.5 / ROW_HEIGHT as f32,
+      width * LANE_WIDTH,
+    );
+    for line in &self.lines {
+      let ((x1, y1), (x2, y2)) = (position(line.from), position(line.to));
+      write!(
+        svg,
+        r#"<line class="lane-{}" x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}"/>"#,
+        line.colour % COLOURS
+      )
+      .unwrap();
+    }
+    write!(
+      svg,
+      r#"<circle class="lane-{}" cx="{}" cy="{}" r="3"/></svg>"#,
+      self.colour % COLOURS,
+      x(self.column),
+      ROW_HEIGHT / 2,
+    )
+    .unwrap();
+    svg
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use git2::{Repository, Signature, Time};
+  use Point::{Bottom, Commit as Dot, Top};
+
+  struct History {
+    _dir: tempfile::TempDir,
+    repo: Repository,
+  }
+
+  impl History {
+    fn new() -> Self {
+      let dir = tempfile::tempdir().unwrap();
+      let repo = Repository::init_bare(dir.path()).unwrap();
+      Self { _dir: dir, repo }
+    }
+
+    /// Commit an empty tree with `parents` at `time`.
+    fn commit(&self, parents: &[Oid], time: i64) -> Oid {
+      let signature = Signature::new("A", "a@example.com", &Time::new(time, 0)).unwrap();
+      let tree = self
+        .repo
+        .find_tree(self.repo.treebuilder(None).unwrap().write().unwrap())
+        .unwrap();
+      let parents = parents
+        .iter()
+        .map(|&parent| self.repo.find_commit(parent).unwrap())
+        .collect::<Vec<_>>();
+      let parents = parents.iter().collect::<Vec<_>>();
+      self
+        .repo
+        .commit(None, &signature, &signature, "", &tree, &parents)
+        .unwrap()
+    }
+
+    fn commits(&self, oids: &[Oid]) -> Vec<Commit<'_>> {
+      oids
+        .iter()
+        .map(|&oid| self.repo.find_commit(oid).unwrap())
+        .collect()
+    }
+  }
+
+  /// The column of the commit and the lines of every row.
+  fn layout(rows: &[Row]) -> Vec<(usize, Vec<(Point, Point)>)> {
+    rows
+      .iter()
+      .map(|row| {
+        let lines = row.lines.iter().map(|line| (line.from, line.to)).collect();
+        (row.column, lines)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn linear_history() {
+    let history = History::new();
+    let first = history.commit(&[], 1);
+    let second = history.commit(&[first], 2);
+    let third = history.commit(&[second], 3);
+
+    let rows = rows(&history.commits(&[third, second, first]));
+    assert_eq!(
+      layout(&rows),
+      [
+        (0, vec![(Dot, Bottom(0))]),
+        (0, vec![(Top(0), Dot), (Dot, Bottom(0))]),
+        (0, vec![(Top(0), Dot)]),
+      ]
+    );
+    assert!(rows.iter().all(|row| row.colour == 0));
+  }
+
+  #[test]
+  fn merge() {
+    let history = History::new();
+    let base = history.commit(&[], 1);
+    let side = history.commit(&[base], 2);
+    let main = history.commit(&[base], 3);
+    let merge = history.commit(&[main, side], 4);
+
+    let rows = rows(&history.commits(&[merge, main, side, base]));
+    assert_eq!(
+      layout(&rows),
+      [
+        (0, vec![(Dot, Bottom(0)), (Dot, Bottom(1))]),
+        (
+          0,
+          vec![(Top(0), Dot), (Top(1), Bottom(1)), (Dot, Bottom(0))]
+        ),
+        // the side branch joins the lane of the base again
+        (
+          1,
+          vec![(Top(1), Dot), (Top(0), Bottom(0)), (Dot, Bottom(0))]
+        ),
+        (0, vec![(Top(0), Dot)]),
+      ]
+    );
+    assert_eq!(rows[1].colour, 0);
+    assert_eq!(rows[2].colour, 1);
+  }
+
+  #[test]
+  fn octopus_merge() {
+    let history = History::new();
+    let first = history.commit(&[], 3);
+    let second = history.commit(&[], 2);
+    let third = history.commit(&[], 1);
+    let merge = history.commit(&[first, second, third], 4);
+
+    let rows = rows(&history.commits(&[merge, first, second, third]));
+    assert_eq!(
+      layout(&rows),
+      [
+        (
+          0,
+          vec![(Dot, Bottom(0)), (Dot, Bottom(1)), (Dot, Bottom(2))]
+        ),
+        // the lanes move left when the first one ends
+        (
+          0,
+          vec![(Top(0), Dot), (Top(1), Bottom(0)), (Top(2), Bottom(1))]
+        ),
+        (0, vec![(Top(0), Dot), (Top(1), Bottom(0))]),
+        (0, vec![(Top(0), Dot)]),
+      ]
+    );
+    let colours = rows.iter().map(|row| row.colour).collect::<Vec<_>>();
+    assert_eq!(colours, [0, 0, 1, 2]);
+  }
+
+  #[test]
+  fn multiple_tips() {
+    let history = History::new();
+    let base = history.commit(&[], 1);
+    let one = history.commit(&[base], 3);
+    let other = history.commit(&[base], 2);
+
+    let rows = rows(&history.commits(&[one, other, base]));
+    assert_eq!(
+      layout(&rows),
+      [
+        (0, vec![(Dot, Bottom(0))]),
+        // a tip no lane leads to starts a new lane
+        (1, vec![(Top(0), Bottom(0)), (Dot, Bottom(0))]),
+        (0, vec![(Top(0), Dot)]),
+      ]
+    );
+    assert_eq!(rows[1].colour, 1);
+  }
+
+  #[test]
+  fn sort_puts_children_first() {
+    let history = History::new();
+    let base = history.commit(&[], 1);
+    // committed on a machine with a clock that is behind
+    let skewed = history.commit(&[base], 0);
+    let other = history.commit(&[base], 2);
+
+    let ids = |commits: Vec<Commit>| commits.iter().map(Commit::id).collect::<Vec<_>>();
+    assert_eq!(
+      ids(sort(history.commits(&[other, base, skewed]))),
+      [other, skewed, base]
+    );
+    // commits that are sorted already stay where they are
+    assert_eq!(
+      ids(sort(history.commits(&[skewed, other, base]))),
+      [skewed, other, base]
+    );
+  }
+}
@@ -0,0 +1,162 @@
+use crate::SYNTAXES;
+use async_std::{
+  channel::{self, Receiver},
+  io::{self, BufRead, Read},
+  stream::Stream,
+};
+use std::{
+  pin::Pin,
+  task::{Context, Poll},
+};
+use syntect::{
+  html::{line_tokens_to_classed_spans, ClassStyle},
+  parsing::{ParseState, ScopeStack, SyntaxReference},
+  util::LinesWithEndings,
+};
+
+/// Templates render this where the streamed part of the page should go.
+pub(crate) const PLACEHOLDER: &str = "<!-- agit:stream -->";
+
+/// Highlights a text one line at a time, yielding the HTML for each line.
+///
+/// Spans that are still open at the end of the text are closed in the HTML of
+/// the last line.
+pub(crate) struct HighlightedLines {
+  text: String,
+  pos: usize,
+  parse_state: ParseState,
+  scope_stack: ScopeStack,
+  open_spans: isize,
+}
+
+impl HighlightedLines {
+  pub(crate) fn new(text: String, syntax: &SyntaxReference) -> Self {
+    Self {
+      text,
+      pos: 0,
+      parse_state: ParseState::new(syntax),
+      scope_stack: ScopeStack::new(),
+      open_spans: 0,
+    }
+  }
+}
+
+impl Iterator for HighlightedLines {
+  type Item = String;
+
+  fn next(&mut self) -> Option<String> {
+    let line = LinesWithEndings::from(&self.text[self.pos..]).next()?;
+    self.pos += line.len();
+
+    let mut html = self
+      .parse_state
+      .parse_line(line, &SYNTAXES)
+      .ok()
+      .and_then(|ops| {
+        line_tokens_to_classed_spans(line, &ops, ClassStyle::Spaced, &mut self.scope_stack).ok()
+      })
+      .map(|(html, delta)| {
+        self.open_spans += delta;
+        html
+      })
+      // show the line without highlighting if the syntax could not handle it
+      .unwrap_or_else(|| askama::MarkupDisplay::new_unsafe(line, askama::Html).to_string());
+
+    if self.pos == self.text.len() {
+      for _ in 0..self.open_spans {
+        html.push_str("</span>");
+      }
+    }
+    Some(html)
+  }
+}
+
+/// Reads HTML chunks from a channel as they are produced.
+struct ChunkReader {
+  chunks: Receiver<String>,
+  buf: Vec<u8>,
+  pos: usize,
+}
+
+impl Read for ChunkReader {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    out: &mut [u8],
+  ) -> Poll<io::Result<usize>> {
+    let available = match self.as_mut().poll_fill_buf(cx) {
+      Poll::Ready(Ok(available)) => available,
+      Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+      Poll::Pending => return Poll::Pending,
+    };
+    let n = available.len().min(out.len());
+    out[..n].copy_from_slice(&available[..n]);
+    self.consume(n);
+    Poll::Ready(Ok(n))
+  }
+}
+
+impl BufRead for ChunkReader {
+  fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+    let this = self.get_mut();
+    while this.pos == this.buf.len() {
+      match Pin::new(&mut this.chunks).poll_next(cx) {
+        Poll::Ready(Some(chunk)) => {
+          this.buf = chunk.into_bytes();
+          this.pos = 0;
+        }
+        // end of the stream
+        Poll::Ready(None) => break,
+        Poll::Pending => return Poll::Pending,
+      }
+    }
+    Poll::Ready(Ok(&this.buf[this.pos..]))
+  }
+
+  fn consume(mut self: Pin<&mut Self>, amt: usize) {
+    self.pos += amt;
+  }
+}
+
+/// Build an HTML response from a rendered page, streaming the chunks produced
+/// by `chunks` in place of the page's [`PLACEHOLDER`].
+///
+/// The chunks are produced on a blocking thread, because highlighting can take
+/// a while and the highlighter state can not be moved between threads. The
+/// thread does not wait for the client to read them, so a slow client can not
+/// hold it; the unread chunks are queued in memory, which is no more than
+/// rendering the whole page up front would take.
+pub(crate) fn stream_page<F, I>(page: String, chunks: F) -> tide::Response
+where
+  F: FnOnce() -> I + Send + 'static,
+  I: Iterator<Item = String>,
+{
+  let (head, tail) = match page.split_once(PLACEHOLDER) {
+    Some((head, tail)) => (head.to_string(), tail.to_string()),
+    None => (page, String::new()),
+  };
+
+  let (sender, receiver) = channel::unbounded();
+  async_std::task::spawn_blocking(move || {
+    let chunks = std::iter::once(head)
+      .chain(chunks())
+      .chain(std::iter::once(tail));
+    for chunk in chunks {
+      if sender.try_send(chunk).is_err() {
+        // the client went away
+        break;
+      }
+    }
+  });
+
+  let reader = ChunkReader {
+    chunks: receiver,
+    buf: Vec::new(),
+    pos: 0,
+  };
+  let mut body = tide::Body::from_reader(reader, None);
+  body.set_mime(tide::http::mime::HTML);
+  let mut response = tide::Response::new(200);
+  response.set_body(body);
+  response
+}
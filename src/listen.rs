@@ -0,0 +1,322 @@
+use crate::Config;
+use async_std::{
+  io::{self, Read, Write},
+  net::{TcpListener, TcpStream},
+  stream::StreamExt,
+  task,
+};
+use futures_rustls::{
+  rustls::{Certificate, PrivateKey, ServerConfig},
+  server, TlsAcceptor,
+};
+use std::{
+  fmt,
+  fs::File,
+  io::BufReader,
+  path::PathBuf,
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+  time::Duration,
+};
+use tide::listener::{ConcurrentListener, ListenInfo, Listener, ToListener};
+
+/// An address from the `listen` option
+#[derive(Debug, PartialEq)]
+pub(crate) enum Address {
+  /// plain HTTP, like `127.0.0.1:8080` or `http://[::1]:8080`
+  Http(String),
+  /// HTTPS using the configured certificate, like `https://[::]:443`
+  Https(String),
+  /// plain HTTP on a Unix domain socket, like `unix:/run/agit.sock`
+  Unix(PathBuf),
+}
+
+impl Address {
+  fn parse(address: &str) -> Result<Self, String> {
+    if let Some(path) = address.strip_prefix("unix:") {
+      return Ok(Self::Unix(path.into()));
+    }
+    let (https, host_port) = match address.strip_prefix("https://") {
+      Some(host_port) => (true, host_port),
+      None => (false, address.strip_prefix("http://").unwrap_or(address)),
+    };
+
+    // check the address without resolving host names
+    match host_port.rsplit_once(':') {
+      Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(if https {
+        Self::Https(host_port.into())
+      } else {
+        Self::Http(host_port.into())
+      }),
+      _ => Err(format!(
+        "invalid listen address {:?}, expected host:port or unix:path",
+        address
+      )),
+    }
+  }
+}
+
+impl fmt::Display for Address {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Http(address) => write!(f, "http://{}", address),
+      Self::Https(address) => write!(f, "https://{}", address),
+      Self::Unix(path) => write!(f, "unix:{}", path.display()),
+    }
+  }
+}
+
+/// The addresses the server should listen on, checking that they are valid.
+pub(crate) fn addresses(config: &Config) -> Result<Vec<Address>, String> {
+  if config.listen.is_empty() {
+    return Ok(vec![Address::Http(format!("0.0.0.0:{}", config.port))]);
+  }
+
+  let addresses = config
+    .listen
+    .iter()
+    .map(|address| Address::parse(address))
+    .collect::<Result<Vec<_>, _>>()?;
+  let https = addresses
+    .iter()
+    .any(|address| matches!(address, Address::Https(_)));
+  if https && (config.tls_cert.is_empty() || config.tls_key.is_empty()) {
+    return Err("listening on https needs tls_cert and tls_key".into());
+  }
+  Ok(addresses)
+}
+
+/// Read the certificate chain and private key from PEM files.
+fn tls_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+  let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+  let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+    .into_iter()
+    .map(Certificate)
+    .collect::<Vec<_>>();
+  if certs.is_empty() {
+    return Err(invalid(format!("no certificates found in {:?}", cert_path)));
+  }
+
+  let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+    .into_iter()
+    .find_map(|item| match item {
+      rustls_pemfile::Item::RSAKey(key)
+      | rustls_pemfile::Item::PKCS8Key(key)
+      | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+      _ => None,
+    })
+    .ok_or_else(|| invalid(format!("no private key found in {:?}", key_path)))?;
+
+  ServerConfig::builder()
+    .with_safe_defaults()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|e| invalid(e.to_string()))
+}
+
+/// Use the sockets passed by systemd socket activation. Sockets named "https"
+/// with `FileDescriptorName=` use TLS.
+#[cfg(unix)]
+fn systemd_listener(config: &Config) -> io::Result<Option<ConcurrentListener<()>>> {
+  use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+  let fds = crate::systemd::listen_fds();
+  if fds.is_empty() {
+    return Ok(None);
+  }
+
+  let mut listener = ConcurrentListener::new();
+  for (fd, name) in fds {
+    // SAFETY: the service manager passed this socket to us and nothing else
+    // in the process uses it
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    match tcp.local_addr() {
+      Ok(address) if name == "https" => listener.add(TlsListener::new(
+        config,
+        address.to_string(),
+        Some(tcp.into()),
+      )?)?,
+      Ok(_) => listener.add(tcp)?,
+      // not an internet socket
+      Err(_) => {
+        listener.add(unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) })?
+      }
+    }
+  }
+  Ok(Some(listener))
+}
+
+/// Set up listeners for all configured addresses, or the sockets passed by
+/// the service manager.
+pub(crate) fn listener(config: &Config) -> io::Result<ConcurrentListener<()>> {
+  #[cfg(unix)]
+  if let Some(listener) = systemd_listener(config)? {
+    return Ok(listener);
+  }
+
+  let addresses = addresses(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+  let mut listener = ConcurrentListener::new();
+  for address in addresses {
+    match address {
+      Address::Http(address) => listener.add(address)?,
+      Address::Https(address) => listener.add(TlsListener::new(config, address, None)?)?,
+      #[cfg(unix)]
+      Address::Unix(path) => {
+        use std::os::unix::fs::FileTypeExt;
+        // a socket left over from a previous run would prevent binding
+        if std::fs::metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+          std::fs::remove_file(&path)?;
+        }
+        listener.add(std::os::unix::net::UnixListener::bind(&path)?)?
+      }
+      #[cfg(not(unix))]
+      Address::Unix(_) => {
+        return Err(io::Error::new(
+          io::ErrorKind::Unsupported,
+          "unix sockets are not supported on this platform",
+        ))
+      }
+    }
+  }
+  Ok(listener)
+}
+
+/// Accepts HTTPS connections on a TCP address.
+struct TlsListener {
+  address: String,
+  acceptor: TlsAcceptor,
+  listener: Option<TcpListener>,
+  server: Option<tide::Server<()>>,
+}
+
+impl TlsListener {
+  /// Set up a listener for `address`, which is bound later unless a bound
+  /// `listener` is given.
+  fn new(config: &Config, address: String, listener: Option<TcpListener>) -> io::Result<Self> {
+    if config.tls_cert.is_empty() || config.tls_key.is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "listening on https needs tls_cert and tls_key",
+      ));
+    }
+    let tls = tls_config(&config.tls_cert, &config.tls_key)?;
+    Ok(Self {
+      address,
+      acceptor: TlsAcceptor::from(Arc::new(tls)),
+      listener,
+      server: None,
+    })
+  }
+}
+
+/// A TLS connection that can be shared by the reading and writing halves of
+/// the HTTP connection.
+#[derive(Clone)]
+struct TlsStream(Arc<Mutex<server::TlsStream<TcpStream>>>);
+
+impl Read for TlsStream {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut *self.0.lock().unwrap()).poll_read(cx, buf)
+  }
+}
+
+impl Write for TlsStream {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+  }
+
+  fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut *self.0.lock().unwrap()).poll_close(cx)
+  }
+}
+
+fn handle_tls(app: tide::Server<()>, acceptor: TlsAcceptor, stream: TcpStream) {
+  task::spawn(async move {
+    let local_addr = stream.local_addr().ok();
+    let peer_addr = stream.peer_addr().ok();
+
+    let stream = match acceptor.accept(stream).await {
+      Ok(stream) => TlsStream(Arc::new(Mutex::new(stream))),
+      Err(e) => {
+        tide::log::warn!("TLS handshake with {:?} failed: {}", peer_addr, e);
+        return;
+      }
+    };
+
+    let result = async_h1::accept(stream, |mut req| async {
+      req.set_local_addr(local_addr);
+      req.set_peer_addr(peer_addr);
+      app.respond(req).await
+    })
+    .await;
+    if let Err(e) = result {
+      tide::log::warn!("error serving {:?}: {}", peer_addr, e);
+    }
+  });
+}
+
+#[async_trait::async_trait]
+impl Listener<()> for TlsListener {
+  async fn bind(&mut self, app: tide::Server<()>) -> io::Result<()> {
+    if self.listener.is_none() {
+      self.listener = Some(TcpListener::bind(&self.address).await?);
+    }
+    self.server = Some(app);
+    Ok(())
+  }
+
+  async fn accept(&mut self) -> io::Result<()> {
+    let listener = self.listener.take().expect("bind has to be called first");
+    let app = self.server.take().expect("bind has to be called first");
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+      match stream {
+        Ok(stream) => handle_tls(app.clone(), self.acceptor.clone(), stream),
+        Err(e) => {
+          tide::log::warn!("could not accept connection on {}: {}", self, e);
+          // e.g. too many open files, give other connections time to finish
+          task::sleep(Duration::from_millis(500)).await;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn info(&self) -> Vec<ListenInfo> {
+    vec![ListenInfo::new(self.to_string(), "tcp".into(), true)]
+  }
+}
+
+impl ToListener<()> for TlsListener {
+  type Listener = Self;
+
+  fn to_listener(self) -> io::Result<Self> {
+    Ok(self)
+  }
+}
+
+impl fmt::Debug for TlsListener {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("TlsListener")
+      .field("address", &self.address)
+      .finish()
+  }
+}
+
+impl fmt::Display for TlsListener {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "https://{}", self.address)
+  }
+}
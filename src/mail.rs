@@ -0,0 +1,569 @@
+//! Reading mailing list archives from a Maildir or mbox, sorting the messages
+//! into threads and finding the commits that patches became.
+
+use git2::{Commit, Diff, Oid, Repository, Time};
+use lazy_static::lazy_static;
+use mail_parser::{mailbox, MessageParser};
+use std::{
+  collections::HashMap,
+  fs, io,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::SystemTime,
+};
+
+pub(crate) struct Email {
+  /// the Message-ID without angle brackets
+  pub(crate) id: String,
+  pub(crate) subject: String,
+  pub(crate) from_name: String,
+  pub(crate) from_address: String,
+  pub(crate) date: Time,
+  pub(crate) body: String,
+  /// the patch id of the diff if this message is a patch
+  pub(crate) patch_id: Option<Oid>,
+  /// the message ids this message replies to, the most specific first
+  parents: Vec<String>,
+}
+
+impl Email {
+  fn parse(raw: &[u8]) -> Option<Self> {
+    let message = MessageParser::default().parse(raw)?;
+    let from = message.from().and_then(|from| from.first());
+    let date = message.date().map_or(Time::new(0, 0), |date| {
+      let offset = i32::from(date.tz_hour) * 60 + i32::from(date.tz_minute);
+      let offset = if date.tz_before_gmt { -offset } else { offset };
+      Time::new(date.to_timestamp(), offset)
+    });
+
+    let mut parents = Vec::new();
+    for header in [message.in_reply_to(), message.references()] {
+      let mut ids = header.as_text_list().unwrap_or_default();
+      // the last reference is the direct parent
+      ids.reverse();
+      parents.extend(ids.into_iter().map(String::from));
+    }
+
+    let mut email = Self {
+      id: message.message_id()?.into(),
+      subject: message.subject().unwrap_or("(no subject)").into(),
+      from_name: from
+        .and_then(|from| from.name())
+        .or_else(|| from.and_then(|from| from.address()))
+        .unwrap_or_default()
+        .into(),
+      from_address: from
+        .and_then(|from| from.address())
+        .unwrap_or_default()
+        .into(),
+      date,
+      body: message.body_text(0).unwrap_or_default().into_owned(),
+      patch_id: None,
+      parents,
+    };
+    if email.patch().is_some() {
+      let (_, diff) = email.split_patch();
+      if !diff.is_empty() {
+        email.patch_id = Diff::from_buffer(diff.as_bytes())
+          .and_then(|diff| diff.patchid(None))
+          .ok();
+      }
+    }
+    Some(email)
+  }
+
+  /// The patch this message contains, if its subject starts with a prefix
+  /// like `[PATCH v2 1/3]`.
+  pub(crate) fn patch(&self) -> Option<PatchSubject<'_>> {
+    let (prefix, summary) = self.subject.strip_prefix('[')?.split_once(']')?;
+    let mut patch = PatchSubject {
+      version: 1,
+      number: 1,
+      total: 1,
+      summary: summary.trim(),
+    };
+    let mut is_patch = false;
+    for word in prefix.split_whitespace() {
+      // also accept the "PATCHv2" spelling
+      let word = match word.to_uppercase().strip_prefix("PATCH") {
+        Some(rest) => {
+          is_patch = true;
+          rest.to_lowercase()
+        }
+        None => word.to_lowercase(),
+      };
+      if let Some(version) = word.strip_prefix('v').and_then(|v| v.parse().ok()) {
+        patch.version = version;
+      } else if let Some((number, total)) = word.split_once('/') {
+        if let (Ok(number), Ok(total)) = (number.parse(), total.parse()) {
+          patch.number = number;
+          patch.total = total;
+        }
+      }
+    }
+    is_patch.then_some(patch)
+  }
+
+  /// Split the body of a patch into the commit message and the diff.
+  pub(crate) fn split_patch(&self) -> (&str, &str) {
+    let body = self.body.as_str();
+    let diff_start = body
+      .match_indices("diff --git ")
+      .map(|(i, _)| i)
+      .find(|&i| i == 0 || body[..i].ends_with('\n'))
+      .unwrap_or(body.len());
+    let (message, diff) = body.split_at(diff_start);
+    // the diffstat follows the "---" line
+    let end = if message.starts_with("---\n") {
+      Some(0)
+    } else {
+      message.find("\n---\n").map(|end| end + 1)
+    };
+    let message = &message[..end.unwrap_or(message.len())];
+    // remove the signature git format-patch adds
+    let diff = match diff.rfind("\n-- \n") {
+      Some(end) => &diff[..end + 1],
+      None => diff,
+    };
+    (message.trim_end(), diff)
+  }
+}
+
+/// The information in the subject prefix of a patch.
+pub(crate) struct PatchSubject<'a> {
+  pub(crate) version: u32,
+  /// 0 for the cover letter
+  pub(crate) number: usize,
+  pub(crate) total: usize,
+  pub(crate) summary: &'a str,
+}
+
+/// A message together with its depth in the reply tree of its thread.
+pub(crate) struct ThreadMessage {
+  pub(crate) depth: usize,
+  pub(crate) email: Email,
+}
+
+pub(crate) struct Thread {
+  /// the messages in the order they are shown, starting with the first message
+  pub(crate) messages: Vec<ThreadMessage>,
+}
+
+impl Thread {
+  pub(crate) fn root(&self) -> &Email {
+    &self.messages[0].email
+  }
+
+  /// The time of the newest message in the thread.
+  pub(crate) fn last_activity(&self) -> Time {
+    self
+      .messages
+      .iter()
+      .map(|message| message.email.date)
+      .max_by_key(Time::seconds)
+      .unwrap()
+  }
+
+  pub(crate) fn contains(&self, id: &str) -> bool {
+    self.messages.iter().any(|message| message.email.id == id)
+  }
+}
+
+/// All threads of a mailbox, shared with the cache.
+pub(crate) type Threads = Arc<Vec<Thread>>;
+
+lazy_static! {
+  /// Threads of the mailboxes that were already read, with the time they were
+  /// last modified at that point.
+  static ref MAILBOXES: Mutex<HashMap<PathBuf, (Option<SystemTime>, Threads)>> = Mutex::default();
+}
+
+/// The mailbox configured with `agit.mailbox` in the git config of the
+/// repository. Relative paths are relative to the git directory.
+pub(crate) fn mailbox(repo: &Repository) -> Option<PathBuf> {
+  let path = repo.config().ok()?.get_path("agit.mailbox").ok()?;
+  Some(repo.path().join(path))
+}
+
+fn is_maildir(path: &Path) -> bool {
+  path.join("cur").is_dir()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  let modified = |path: PathBuf| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+  if is_maildir(path) {
+    // new messages are added to one of these directories
+    modified(path.join("cur")).max(modified(path.join("new")))
+  } else {
+    modified(path.to_path_buf())
+  }
+}
+
+fn read(path: &Path) -> io::Result<Vec<Email>> {
+  let raw = if is_maildir(path) {
+    mailbox::maildir::MessageIterator::new(path)?
+      .map(|message| Ok(message?.unwrap_contents()))
+      .collect::<io::Result<Vec<_>>>()?
+  } else {
+    mailbox::mbox::MessageIterator::new(fs::File::open(path)?)
+      .filter_map(Result::ok)
+      .map(|message| message.unwrap_contents())
+      .collect()
+  };
+  Ok(raw.iter().filter_map(|raw| Email::parse(raw)).collect())
+}
+
+/// Sort messages into threads using their `In-Reply-To` and `References`
+/// headers. Replies to messages that are not in the mailbox start a new thread.
+fn thread(mut emails: Vec<Email>) -> Vec<Thread> {
+  emails.sort_by_key(|email| email.date.seconds());
+  // the first message wins if several have the same id
+  let mut index = HashMap::new();
+  for (i, email) in emails.iter().enumerate() {
+    index.entry(email.id.clone()).or_insert(i);
+  }
+
+  let mut children = vec![Vec::new(); emails.len()];
+  let mut roots = Vec::new();
+  for (i, email) in emails.iter().enumerate() {
+    match email
+      .parents
+      .iter()
+      .find_map(|id| index.get(id).filter(|&&parent| parent != i))
+    {
+      Some(&parent) => children[parent].push(i),
+      None => roots.push(i),
+    }
+  }
+
+  // Messages in a cycle of replies are not reachable from any root, so the
+  // oldest unvisited message is used as a root until all are visited.
+  let mut visited = vec![false; emails.len()];
+  let mut order = Vec::new();
+  let mut roots = roots.into_iter();
+  loop {
+    let root = match roots
+      .next()
+      .or_else(|| visited.iter().position(|visited| !visited))
+    {
+      Some(root) if !visited[root] => root,
+      Some(_) => continue,
+      None => break,
+    };
+    let mut thread = Vec::new();
+    let mut stack = vec![(0, root)];
+    while let Some((depth, i)) = stack.pop() {
+      if std::mem::replace(&mut visited[i], true) {
+        continue;
+      }
+      thread.push((depth, i));
+      // reversed so the oldest reply is shown first
+      stack.extend(children[i].iter().rev().map(|&child| (depth + 1, child)));
+    }
+    order.push(thread);
+  }
+
+  let mut emails = emails.into_iter().map(Some).collect::<Vec<_>>();
+  let mut threads = order
+    .into_iter()
+    .map(|thread| Thread {
+      messages: thread
+        .into_iter()
+        .map(|(depth, i)| ThreadMessage {
+          depth,
+          email: emails[i].take().unwrap(),
+        })
+        .collect(),
+    })
+    .collect::<Vec<_>>();
+  // most recently active threads first
+  threads.sort_by_key(|thread| std::cmp::Reverse(thread.last_activity().seconds()));
+  threads
+}
+
+/// The threads in the mailbox at `path`. The mailbox is only read again when
+/// it changed.
+pub(crate) fn threads(path: &Path) -> io::Result<Threads> {
+  let modified = modified(path);
+  if let Some((cached, threads)) = MAILBOXES.lock().unwrap().get(path) {
+    if modified.is_some() && *cached == modified {
+      return Ok(threads.clone());
+    }
+  }
+
+  let threads = Arc::new(thread(read(path)?));
+  MAILBOXES
+    .lock()
+    .unwrap()
+    .insert(path.to_path_buf(), (modified, threads.clone()));
+  Ok(threads)
+}
+
+/// The patches of one version of a patch series, and its cover letter.
+pub(crate) struct Series<'a> {
+  pub(crate) thread: &'a Thread,
+  pub(crate) version: u32,
+  pub(crate) total: usize,
+  pub(crate) cover: Option<&'a Email>,
+  /// the patches that were found, ordered by their number
+  pub(crate) patches: Vec<&'a Email>,
+}
+
+impl<'a> Series<'a> {
+  /// The cover letter, or the first patch if there is none.
+  pub(crate) fn first(&self) -> &'a Email {
+    self.cover.unwrap_or_else(|| self.patches[0])
+  }
+
+  pub(crate) fn title(&self) -> &'a str {
+    self.first().patch().unwrap().summary
+  }
+
+  pub(crate) fn is_complete(&self) -> bool {
+    self.patches.len() == self.total
+  }
+
+  /// The title and the summaries of all patches, in lower case.
+  fn summaries(&self) -> Vec<String> {
+    std::iter::once(self.title())
+      .chain(
+        self
+          .patches
+          .iter()
+          .map(|patch| patch.patch().unwrap().summary),
+      )
+      .map(str::to_lowercase)
+      .collect()
+  }
+
+  /// Whether `other` is another version of the same series, because it was
+  /// sent by the same author in the same thread, with the same title or with
+  /// a patch with the same summary.
+  fn is_reroll_of(&self, other: &Series) -> bool {
+    self.first().from_address == other.first().from_address
+      && (std::ptr::eq(self.thread, other.thread) || {
+        let theirs = other.summaries();
+        self
+          .summaries()
+          .iter()
+          .any(|summary| theirs.contains(summary))
+      })
+  }
+}
+
+/// Find the patch series in `threads`, grouped with their other versions.
+///
+/// The groups are ordered by their newest version and the versions of each
+/// group by their version number.
+pub(crate) fn series(threads: &[Thread]) -> Vec<Vec<Series<'_>>> {
+  let mut all = Vec::new();
+  for thread in threads {
+    let mut in_thread: Vec<Series> = Vec::new();
+    // replies to patches have subjects starting with "Re:" so they are not
+    // taken for patches
+    for message in &thread.messages {
+      let email = &message.email;
+      let patch = match email.patch() {
+        Some(patch) if patch.number <= patch.total => patch,
+        _ => continue,
+      };
+      let series = match in_thread.iter_mut().find(|series| {
+        series.version == patch.version
+          && series.total == patch.total
+          && series.first().from_address == email.from_address
+      }) {
+        Some(series) => series,
+        None => {
+          in_thread.push(Series {
+            thread,
+            version: patch.version,
+            total: patch.total,
+            cover: None,
+            patches: Vec::new(),
+          });
+          in_thread.last_mut().unwrap()
+        }
+      };
+      if patch.number == 0 {
+        series.cover.get_or_insert(email);
+      } else if !series
+        .patches
+        .iter()
+        .any(|other| other.patch().unwrap().number == patch.number)
+      {
+        series.patches.push(email);
+      }
+    }
+    // a cover letter without any patches is not much of a series
+    all.extend(
+      in_thread
+        .into_iter()
+        .filter(|series| !series.patches.is_empty()),
+    );
+  }
+  for series in &mut all {
+    series
+      .patches
+      .sort_by_key(|patch| patch.patch().unwrap().number);
+  }
+
+  // threads are ordered newest first, so go through the series oldest first
+  let mut groups: Vec<Vec<Series>> = Vec::new();
+  for series in all.into_iter().rev() {
+    match groups
+      .iter_mut()
+      .find(|group| group.iter().any(|other| series.is_reroll_of(other)))
+    {
+      Some(group) => group.push(series),
+      None => groups.push(vec![series]),
+    }
+  }
+  for group in &mut groups {
+    group.sort_by_key(|series| series.version);
+  }
+  groups.sort_by_key(|group| {
+    std::cmp::Reverse(
+      group
+        .iter()
+        .map(|series| series.first().date.seconds())
+        .max(),
+    )
+  });
+  groups
+}
+
+/// The patch ids of the commits in a repository, and the commits the
+/// revision walk started at to find them.
+#[derive(Default)]
+struct PatchIds {
+  tips: Vec<Oid>,
+  commits: HashMap<Oid, Oid>,
+}
+
+lazy_static! {
+  /// The patch ids of repositories, by the path of the repository.
+  static ref PATCH_IDS: Mutex<HashMap<PathBuf, PatchIds>> = Mutex::default();
+}
+
+/// The patch id of the changes a commit made compared to its first parent,
+/// like `git patch-id --stable` computes it.
+pub(crate) fn commit_patch_id(repo: &Repository, commit: &Commit) -> Option<Oid> {
+  let parent_tree = commit.parent(0).ok().and_then(|parent| parent.tree().ok());
+  let mut diff = repo
+    .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree().ok()?), None)
+    .ok()?;
+  // like git format-patch does by default
+  diff
+    .find_similar(Some(git2::DiffFindOptions::new().renames(true)))
+    .ok()?;
+  diff.patchid(None).ok()
+}
+
+/// Keep the patch ids of the repositories with a mailing list up to date in the
+/// background, so pages don't have to wait for them.
+pub(crate) fn start() {
+  let changes = crate::watch::subscribe();
+  std::thread::spawn(move || {
+    for repo in crate::exported_repos() {
+      index_patch_ids(&repo);
+    }
+    for change in changes {
+      if let Ok(repo) = Repository::open(&change.path) {
+        index_patch_ids(&repo);
+      }
+    }
+  });
+}
+
+/// The key of a repository in `PATCH_IDS`, which is the same however the
+/// repository was opened.
+fn patch_ids_key(repo: &Repository) -> PathBuf {
+  repo
+    .path()
+    .canonicalize()
+    .unwrap_or_else(|_| repo.path().to_path_buf())
+}
+
+/// Remember the patch ids of the commits reachable from HEAD, if `repo` has a
+/// mailing list. Only commits that are new since the last call are looked at.
+///
+/// Computing a patch id takes a diff, so every call looks at no more than
+/// `max_revwalk_depth` commits. Commits older than that are never indexed, and
+/// patches that were applied as them are not matched.
+fn index_patch_ids(repo: &Repository) {
+  if mailbox(repo).is_none() {
+    return;
+  }
+  let head = match repo.head().ok().and_then(|head| head.target()) {
+    Some(head) => head,
+    None => return,
+  };
+  let key = patch_ids_key(repo);
+
+  let tips = PATCH_IDS
+    .lock()
+    .unwrap()
+    .get(&key)
+    .map(|known| known.tips.clone())
+    .unwrap_or_default();
+  if tips.contains(&head) {
+    return;
+  }
+  // start over if the history was rewritten
+  let rewritten = !tips
+    .iter()
+    .all(|&tip| repo.graph_descendant_of(head, tip).unwrap_or(false));
+  let tips = if rewritten { Vec::new() } else { tips };
+
+  // the lock is not held while walking, so pages can still use the old ids
+  let mut commits = HashMap::new();
+  let revwalk = repo.revwalk().and_then(|mut revwalk| {
+    revwalk.push(head)?;
+    for &tip in &tips {
+      revwalk.hide(tip)?;
+    }
+    Ok(revwalk)
+  });
+  for commit in revwalk
+    .into_iter()
+    .flatten()
+    .take(crate::config().max_revwalk_depth)
+    .filter_map(|oid| repo.find_commit(oid.ok()?).ok())
+    // merges do not correspond to a patch
+    .filter(|commit| commit.parent_count() <= 1)
+  {
+    if let Some(patch_id) = commit_patch_id(repo, &commit) {
+      commits.insert(patch_id, commit.id());
+    }
+  }
+
+  let mut cache = PATCH_IDS.lock().unwrap();
+  let known = cache.entry(key).or_default();
+  if rewritten {
+    *known = PatchIds::default();
+  }
+  // the oldest commit with a patch id is the one the patch became
+  for (patch_id, commit) in commits {
+    known.commits.entry(patch_id).or_insert(commit);
+  }
+  known.tips = vec![head];
+}
+
+/// Find the commits reachable from HEAD that have one of the patch ids in
+/// `patch_ids`.
+///
+/// The patch ids are found in the background after HEAD changes, so commits
+/// that were just pushed might be missing.
+pub(crate) fn applied_commits(
+  repo: &Repository,
+  patch_ids: impl Iterator<Item = Oid>,
+) -> HashMap<Oid, Oid> {
+  let cache = PATCH_IDS.lock().unwrap();
+  let known = match cache.get(&patch_ids_key(repo)) {
+    Some(known) => known,
+    None => return HashMap::new(),
+  };
+  patch_ids
+    .filter_map(|patch_id| Some((patch_id, *known.commits.get(&patch_id)?)))
+    .collect()
+}
@@ -2,19 +2,51 @@
 
 use crate::route_prelude::*;
 use git2::Tree;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::BTreeMap,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+  },
+  time::{Duration, Instant},
+};
 use syntect::parsing::SyntaxSet;
 
-use tide::Request;
+use async_std::prelude::FutureExt;
+use tide::{listener::Listener, Request};
 
+pub(crate) mod accesslog;
+pub(crate) mod cli;
 pub(crate) mod errorpage;
+pub(crate) mod export;
 pub(crate) mod filters;
+pub(crate) mod graph;
+pub(crate) mod highlight;
+pub(crate) mod listen;
+pub(crate) mod mail;
+pub(crate) mod metrics;
+pub(crate) mod mirror;
+pub(crate) mod notify;
+pub(crate) mod refs;
 pub(crate) mod routes;
-
-#[derive(Deserialize, Debug)]
+pub(crate) mod shutdown;
+#[cfg(unix)]
+pub(crate) mod systemd;
+pub(crate) mod verify;
+pub(crate) mod watch;
+pub(crate) mod webhooks;
+
+#[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct Config {
   #[serde(default = "defaults::port")]
   port: u16,
+  #[serde(default = "Vec::new")]
+  listen: Vec<String>,
+  #[serde(default = "String::new")]
+  tls_cert: String,
+  #[serde(default = "String::new")]
+  tls_key: String,
   #[serde(default = "defaults::repo_directory")]
   repos_root: String,
   #[serde(default = "String::new")]
@@ -25,8 +57,196 @@ pub(crate) struct Config {
   export_ok: String,
   #[serde(default = "String::new")]
   clone_base: String,
+  #[serde(default = "String::new")]
+  base_url: String,
+  #[serde(default = "String::new")]
+  base_path: String,
   #[serde(default = "defaults::log_per_page")]
   log_per_page: usize,
+  #[serde(default = "defaults::max_highlight_size")]
+  max_highlight_size: usize,
+  #[serde(default = "defaults::max_diff_size")]
+  max_diff_size: usize,
+  #[serde(default = "defaults::max_revwalk_depth")]
+  max_revwalk_depth: usize,
+  #[serde(default = "defaults::max_blob_size")]
+  max_blob_size: usize,
+  #[serde(default = "defaults::max_archive_size")]
+  max_archive_size: usize,
+  #[serde(default = "defaults::request_timeout")]
+  request_timeout: u64,
+  #[serde(default = "defaults::feed_content")]
+  feed_content: Vec<FeedContent>,
+  #[serde(default = "defaults::access_log")]
+  access_log: AccessLogFormat,
+  #[serde(default = "defaults::shutdown_timeout")]
+  shutdown_timeout: u64,
+  #[serde(default = "String::new")]
+  sendmail: String,
+  #[serde(default = "String::new")]
+  smtp_host: String,
+  #[serde(default = "defaults::notify_from")]
+  notify_from: String,
+  #[serde(default = "String::new")]
+  push_ssh_key: String,
+  // the tokens are not shown by --print-config
+  #[serde(default, serialize_with = "redacted")]
+  push_tokens: BTreeMap<String, String>,
+  #[serde(default = "String::new")]
+  gpg_keyring: String,
+  #[serde(default = "String::new")]
+  ssh_allowed_signers: String,
+}
+
+/// What to show as the content of a commit in feeds
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FeedContent {
+  /// the full commit message
+  Message,
+  /// the list of changed files
+  Diffstat,
+}
+
+/// How requests are logged to standard output
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AccessLogFormat {
+  /// requests are not logged
+  Off,
+  /// the Common Log Format used by many web servers
+  Common,
+  /// one JSON object per line
+  Json,
+}
+
+impl Config {
+  /// Read the configuration file and apply the overrides from the command
+  /// line, exiting if the configuration is invalid.
+  fn load(args: &cli::Args) -> Self {
+    Self::read(args, false).unwrap_or_else(|e| {
+      eprintln!("{}", e);
+      std::process::exit(1);
+    })
+  }
+
+  /// Read and validate the configuration file and apply the overrides from
+  /// the environment and the command line.
+  ///
+  /// Later sources take precedence: the defaults, the configuration file,
+  /// `AGIT_*` environment variables, `--set` and the other options.
+  ///
+  /// A missing configuration file means the defaults are used, except when
+  /// `reloading`, since the file is probably just being replaced.
+  fn read(args: &cli::Args, reloading: bool) -> Result<Self, String> {
+    let toml_text = match fs::read_to_string(&args.config_filename) {
+      Ok(toml_text) => toml_text,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound && !reloading => {
+        tide::log::warn!(
+          "configuration file {:?} not found, using defaults",
+          args.config_filename
+        );
+        String::new()
+      }
+      Err(e) => {
+        return Err(format!(
+          "could not read configuration file {:?}: {}",
+          args.config_filename, e
+        ))
+      }
+    };
+    let mut table = toml_text
+      .parse::<toml::Table>()
+      .map_err(|e| format!("could not parse configuration file: {}", e))?;
+
+    let defaults = toml::Table::try_from(toml::from_str::<Config>("").unwrap()).unwrap();
+    let mut env_vars = std::env::vars()
+      .filter_map(|(name, value)| {
+        let key = name.strip_prefix("AGIT_")?.to_lowercase();
+        // the location of the configuration file itself
+        if key == "config" {
+          return None;
+        }
+        // other programs might use variables starting with AGIT_ too
+        if !defaults.contains_key(&key) {
+          tide::log::warn!("ignoring {}: unknown configuration option {:?}", name, key);
+          return None;
+        }
+        Some((name, key, value))
+      })
+      .collect::<Vec<_>>();
+    env_vars.sort();
+    let overrides = env_vars
+      .into_iter()
+      .chain(
+        args
+          .overrides
+          .iter()
+          .map(|(key, value)| (format!("--set {}", key), key.clone(), value.clone())),
+      )
+      .chain(
+        args
+          .port
+          .map(|port| ("--port".into(), "port".into(), port.to_string())),
+      )
+      .chain(
+        args
+          .repos_root
+          .clone()
+          .map(|root| ("--repos-root".into(), "repos_root".into(), root)),
+      );
+    for (source, key, value) in overrides {
+      let value = match defaults.get(&key) {
+        None => {
+          return Err(format!(
+            "{}: unknown configuration option {:?}",
+            source, key
+          ))
+        }
+        // strings don't have to be quoted
+        Some(toml::Value::String(_)) => toml::Value::String(value),
+        // and lists of strings can be separated by commas
+        Some(toml::Value::Array(_)) if !value.trim_start().starts_with('[') => toml::Value::Array(
+          value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| toml::Value::String(item.into()))
+            .collect(),
+        ),
+        Some(_) => format!("value = {}", value)
+          .parse::<toml::Table>()
+          .ok()
+          .and_then(|mut table| table.remove("value"))
+          .ok_or_else(|| format!("{}: invalid value {:?}", source, value))?,
+      };
+      table.insert(key, value);
+    }
+
+    let mut config: Config = toml::Value::Table(table)
+      .try_into()
+      .map_err(|e| format!("invalid configuration: {}", e))?;
+
+    // no trailing slash, so paths can be appended
+    config.base_path = config.base_path.trim_end_matches('/').to_string();
+    if !config.base_path.is_empty() && !config.base_path.starts_with('/') {
+      config.base_path.insert(0, '/');
+    }
+
+    if config.log_per_page == 0 {
+      return Err("invalid configuration: log_per_page has to be at least 1".into());
+    }
+    listen::addresses(&config).map_err(|e| format!("invalid configuration: {}", e))?;
+    Ok(config)
+  }
+}
+
+/// Serialize secrets like tokens without their values.
+fn redacted<S: serde::Serializer>(
+  secrets: &BTreeMap<String, String>,
+  serializer: S,
+) -> Result<S::Ok, S::Error> {
+  serializer.collect_map(secrets.keys().map(|key| (key, "<redacted>")))
 }
 
 /// Defaults for the configuration options
@@ -51,20 +271,53 @@ mod defaults {
   pub(crate) fn log_per_page() -> usize {
     100
   }
-}
 
-const HELP: &str = "
-Usage: agit
+  pub(crate) fn max_highlight_size() -> usize {
+    1024 * 1024
+  }
 
-FLAGS:
-  -h, --help            Prints this help information and exits.
-OPTIONS:
-  -c, --config <FILE>   Use a specific configuration file.
-                        default is ./agit.toml
-";
+  pub(crate) fn max_diff_size() -> usize {
+    1024 * 1024
+  }
+
+  pub(crate) fn max_revwalk_depth() -> usize {
+    10_000
+  }
+
+  pub(crate) fn max_blob_size() -> usize {
+    1024 * 1024
+  }
+
+  pub(crate) fn max_archive_size() -> usize {
+    100 * 1024 * 1024
+  }
+
+  pub(crate) fn request_timeout() -> u64 {
+    60
+  }
+
+  pub(crate) fn feed_content() -> Vec<super::FeedContent> {
+    vec![super::FeedContent::Message]
+  }
+
+  pub(crate) fn access_log() -> super::AccessLogFormat {
+    super::AccessLogFormat::Common
+  }
+
+  pub(crate) fn shutdown_timeout() -> u64 {
+    30
+  }
+
+  pub(crate) fn notify_from() -> String {
+    "agit@localhost".into()
+  }
+}
 
 lazy_static! {
-  pub(crate) static ref CONFIG: Config = args();
+  static ref ARGS: cli::Args = cli::parse();
+
+  // replaced as a whole when the configuration is reloaded, see `watch_config`
+  static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::load(&ARGS)));
 
   // so we only have to load this once to reduce startup time for syntax highlighting
   pub(crate) static ref SYNTAXES: SyntaxSet = {
@@ -82,47 +335,77 @@ lazy_static! {
   };
 }
 
-#[derive(rust_embed::RustEmbed)]
-#[folder = "$CARGO_MANIFEST_DIR/templates/static"]
-struct StaticDir;
-
-fn args() -> Config {
-  // TODO cli
+/// The current configuration.
+///
+/// Hold on to the returned value instead of calling this repeatedly if the
+/// values have to be consistent with each other, since the configuration may
+/// be reloaded at any time.
+pub(crate) fn config() -> Arc<Config> {
+  CONFIG.read().unwrap().clone()
+}
 
-  let mut pargs = pico_args::Arguments::from_env();
+/// Read the configuration file again and use it if it is valid, otherwise keep
+/// using the current configuration.
+fn reload_config() {
+  let mut new = match Config::read(&ARGS, true) {
+    Ok(new) => new,
+    Err(e) => {
+      tide::log::error!("{}, keeping the previous configuration", e);
+      return;
+    }
+  };
 
-  if pargs.contains(["-h", "--help"]) {
-    print!("{HELP}");
-    std::process::exit(0);
+  let old = config();
+  if (&new.port, &new.listen, &new.tls_cert, &new.tls_key)
+    != (&old.port, &old.listen, &old.tls_cert, &old.tls_key)
+  {
+    tide::log::warn!("changing the addresses to listen on only takes effect after a restart");
+  }
+  if new.base_path != old.base_path {
+    // the routes can't be changed while running
+    tide::log::warn!("changing base_path only takes effect after a restart");
+    new.base_path = old.base_path.clone();
   }
+  *CONFIG.write().unwrap() = Arc::new(new);
+  // logged as a warning, because only warnings and errors are logged
+  tide::log::warn!("reloaded configuration from {:?}", ARGS.config_filename);
+}
 
-  let config_filename = pargs
-    .opt_value_from_str(["-c", "--config"])
-    .unwrap()
-    .unwrap_or_else(|| "agit.toml".to_string());
-
-  let toml_text = fs::read_to_string(&config_filename).unwrap_or_else(|_| {
-    tide::log::warn!(
-      "configuration file {:?} not found, using defaults",
-      config_filename
-    );
-    String::new()
-  });
-  match toml::from_str(&toml_text) {
-    Ok(config) => config,
-    Err(e) => {
-      tide::log::error!("could not parse configuration file: {}", e);
-      std::process::exit(1);
+/// Reload the configuration when the process receives SIGHUP or when the
+/// configuration file is modified.
+fn watch_config() -> Result<(), std::io::Error> {
+  let hangup = Arc::new(AtomicBool::new(false));
+  signal_hook::flag::register(signal_hook::consts::SIGHUP, hangup.clone())?;
+
+  let modified = || {
+    fs::metadata(&ARGS.config_filename)
+      .and_then(|metadata| metadata.modified())
+      .ok()
+  };
+  let mut last_modified = modified();
+
+  std::thread::spawn(move || loop {
+    std::thread::sleep(Duration::from_secs(1));
+    let now_modified = modified();
+    if hangup.swap(false, Ordering::Relaxed) || now_modified != last_modified {
+      last_modified = now_modified;
+      reload_config();
     }
-  }
+  });
+  Ok(())
 }
 
+#[derive(rust_embed::RustEmbed)]
+#[folder = "$CARGO_MANIFEST_DIR/templates/static"]
+struct StaticDir;
+
 pub(crate) fn repo_from_request(repo_name: &str) -> Result<Repository, tide::Error> {
   let repo_name = percent_encoding::percent_decode_str(repo_name)
     .decode_utf8_lossy()
     .into_owned();
+  let config = config();
 
-  let repo_path = Path::new(&CONFIG.repos_root)
+  let repo_path = Path::new(&config.repos_root)
     .join(repo_name)
     .canonicalize()?;
 
@@ -130,15 +413,79 @@ pub(crate) fn repo_from_request(repo_name: &str) -> Result<Repository, tide::Err
     .ok()
     // outside users should not be able to tell the difference between
     // nonexistent and existing but forbidden repos, so not using 403
-    .filter(|repo| repo.path().join(&CONFIG.export_ok).exists())
+    .filter(|repo| repo.path().join(&config.export_ok).exists())
     .ok_or_else(|| tide::Error::from_str(404, "this repository does not exist."))
 }
 
+/// Check whether `commit` changed any of the files matched by the pathspec in
+/// `options` compared to any of its parents.
+pub(crate) fn commit_touches(
+  repo: &Repository,
+  commit: &Commit,
+  options: &mut DiffOptions,
+) -> bool {
+  let tree = commit.tree().unwrap();
+  if commit.parent_count() == 0 {
+    repo
+      .diff_tree_to_tree(None, Some(&tree), Some(options))
+      .unwrap()
+      .stats()
+      .unwrap()
+      .files_changed()
+      > 0
+  } else {
+    // check that the given file was affected from any of the parents
+    commit.parents().any(|parent| {
+      repo
+        .diff_tree_to_tree(parent.tree().ok().as_ref(), Some(&tree), Some(options))
+        .unwrap()
+        .stats()
+        .unwrap()
+        .files_changed()
+        > 0
+    })
+  }
+}
+
+/// The URL the site is publicly reachable at, without a trailing slash.
+///
+/// Uses the configured `base_url`, or the origin the request was sent to
+/// followed by the `base_path`.
+pub(crate) fn base_url<State>(req: &Request<State>) -> String {
+  let config = config();
+  if config.base_url.is_empty() {
+    req.url().origin().ascii_serialization() + &config.base_path
+  } else {
+    config.base_url.trim_end_matches('/').into()
+  }
+}
+
+/// All repositories in the repositories root that are exported.
+pub(crate) fn exported_repos() -> Vec<Repository> {
+  let config = config();
+  fs::read_dir(&config.repos_root)
+    .map(|entries| {
+      entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter_map(|entry| Repository::open(entry).ok())
+        .filter(|repo| {
+          // check for the export file in the git directory
+          // (the .git subfolder for non-bare repos)
+          repo.path().join(&config.export_ok).exists()
+        })
+        .collect::<Vec<_>>()
+    })
+    .map_err(|e| tide::log::warn!("can't read repositories: {}", e))
+    .unwrap_or_default()
+}
+
+/// Find the most recent commit that changed `path`, looking at no more than
+/// `max_revwalk_depth` commits and for no longer than `request_timeout`.
 fn last_commit_for<'a, S: git2::IntoCString>(
   repo: &'a Repository,
   spec: &str,
   path: S,
-) -> Commit<'a> {
+) -> Option<Commit<'a>> {
   let mut revwalk = repo.revwalk().unwrap();
   revwalk
     .push(
@@ -156,32 +503,13 @@ fn last_commit_for<'a, S: git2::IntoCString>(
   let mut options = DiffOptions::new();
   options.pathspec(path);
 
+  let config = config();
+  let deadline = Instant::now() + Duration::from_secs(config.request_timeout);
   revwalk
+    .take(config.max_revwalk_depth)
+    .take_while(|_| Instant::now() < deadline)
     .filter_map(|oid| repo.find_commit(oid.unwrap()).ok()) // TODO error handling
-    .find(|commit| {
-      let tree = commit.tree().unwrap();
-      if commit.parent_count() == 0 {
-        repo
-          .diff_tree_to_tree(None, Some(&tree), Some(&mut options))
-          .unwrap()
-          .stats()
-          .unwrap()
-          .files_changed()
-          > 0
-      } else {
-        // check that the given file was affected from any of the parents
-        commit.parents().any(|parent| {
-          repo
-            .diff_tree_to_tree(parent.tree().ok().as_ref(), Some(&tree), Some(&mut options))
-            .unwrap()
-            .stats()
-            .unwrap()
-            .files_changed()
-            > 0
-        })
-      }
-    })
-    .expect("file was not part of any commit")
+    .find(|commit| commit_touches(repo, commit, &mut options))
 }
 
 #[derive(Template)]
@@ -191,7 +519,7 @@ struct RepoTreeTemplate<'a> {
   tree: Tree<'a>,
   path: &'a Path,
   spec: &'a str,
-  last_commit: Commit<'a>,
+  last_commit: Option<Commit<'a>>,
 }
 
 async fn git_data(req: Request<()>) -> tide::Result {
@@ -199,7 +527,11 @@ async fn git_data(req: Request<()>) -> tide::Result {
   let path = req
     .url()
     .path()
-    .strip_prefix(&format!("/{}/", req.param("repo_name").unwrap()))
+    .strip_prefix(&format!(
+      "{}/{}/",
+      config().base_path,
+      req.param("repo_name").unwrap()
+    ))
     .unwrap_or_default();
   let path = repo.path().join(path).canonicalize()?;
 
@@ -226,97 +558,203 @@ async fn git_data(req: Request<()>) -> tide::Result {
 
 #[async_std::main]
 async fn main() -> Result<(), std::io::Error> {
-  if let Err(error) = fs::create_dir_all(CONFIG.repos_root.clone()) {
+  match &ARGS.command {
+    cli::Command::Serve => serve().await,
+    cli::Command::Check => cli::check(),
+    cli::Command::PrintConfig => cli::print_config(),
+    cli::Command::Init {
+      name,
+      description,
+      mirror,
+    } => cli::init(name, description.as_deref(), mirror.as_deref()),
+    cli::Command::Render { path } => cli::render(path).await,
+    cli::Command::Export {
+      outdir,
+      refs,
+      force,
+    } => export::export(outdir, refs, *force).await,
+  }
+}
+
+async fn serve() -> Result<(), std::io::Error> {
+  // changes the environment, so before any threads are started
+  #[cfg(unix)]
+  systemd::take_listen_fds();
+  tide::log::with_level(tide::log::LevelFilter::Warn);
+  if let Err(error) = fs::create_dir_all(&config().repos_root) {
     tide::log::error!("error creating repositories root: {}", error);
     std::process::exit(1);
   }
 
+  watch_config()?;
+  notify::start();
+  mail::start();
+  webhooks::start();
+  watch::start();
+  mirror::start();
+  mirror::start_pushing();
+
+  let mut listener = listen::listener(&config())?;
+  listener.bind(app()).await?;
+  for info in listener.info() {
+    println!("running on {}", info);
+  }
+  #[cfg(unix)]
+  systemd::notify("READY=1");
+
+  // stop accepting connections when asked to stop
+  listener.accept().race(shutdown::signal()).await?;
+  drop(listener);
+
+  #[cfg(unix)]
+  systemd::notify("STOPPING=1");
+  shutdown::drain(Duration::from_secs(config().shutdown_timeout)).await;
+  Ok(())
+}
+
+/// Set up all the routes of the site.
+pub(crate) fn app() -> tide::Server<()> {
   let mut app = tide::new();
 
+  // outermost, so it sees the error pages too
+  app.with(shutdown::InFlight);
+  app.with(accesslog::AccessLog);
   app.with(errorpage::ErrorToErrorpage);
 
-  app.at("/").get(routes::index);
+  // all routes are below the base path
+  let base_path = config().base_path.clone();
+  let path = |path: &str| format!("{}{}", base_path, path);
 
-  // repositories
+  if !base_path.is_empty() {
+    app.at(&base_path).get(routes::index);
+  }
+  app.at(&path("/")).get(routes::index);
+  app.at(&path("/activity")).get(routes::activity);
+  app.at(&path("/feed.xml")).get(routes::activity_feed);
+  app.at(&path("/metrics")).get(routes::metrics);
+  app.at(&path("/healthz")).get(routes::healthz);
+  app.at(&path("/readyz")).get(routes::readyz);
+
+  // JSON API
+  app.at(&path("/api/v1/repos")).get(routes::api_repos);
+  app
+    .at(&path("/api/v1/:repo_name/refs"))
+    .get(routes::api_refs);
+  app
+    .at(&path("/api/v1/:repo_name/commits"))
+    .get(routes::api_commits);
+  app
+    .at(&path("/api/v1/:repo_name/commit/:commit"))
+    .get(routes::api_commit);
   app
-    .at("/:repo_name")
-    .get(routes::repo_home)
-    .at("/:repo_name/")
-    .get(routes::repo_home);
+    .at(&path("/api/v1/:repo_name/tree/:ref"))
+    .get(routes::api_tree);
+  app
+    .at(&path("/api/v1/:repo_name/tree/:ref/*object_name"))
+    .get(routes::api_tree);
+  app
+    .at(&path("/api/v1/:repo_name/blob/:ref/*object_name"))
+    .get(routes::api_blob);
+
+  // repositories
+  // Note that `Route::at` nests paths, so every route is added to `app`
+  // separately instead of chaining them.
+  app.at(&path("/:repo_name")).get(routes::repo_home);
+  app.at(&path("/:repo_name/")).get(routes::repo_home);
 
   // git clone stuff
-  app
-    .at("/:repo_name/info/refs")
-    .get(git_data)
-    .at("/:repo_name/HEAD")
-    .get(git_data)
-    .at("/:repo_name/objects/*obj")
-    .get(git_data);
+  app.at(&path("/:repo_name/info/refs")).get(git_data);
+  app.at(&path("/:repo_name/HEAD")).get(git_data);
+  app.at(&path("/:repo_name/objects/*obj")).get(git_data);
 
   // web pages
   app
-    .at("/:repo_name/commit/:commit")
-    .get(routes::repo_commit)
-    .at("/:repo_name/refs")
-    .get(routes::repo_refs)
-    .at("/:repo_name/refs/")
-    .get(routes::repo_refs)
-    .at("/:repo_name/refs.xml")
-    .get(routes::repo_refs_feed)
-    .at("/:repo_name/refs/:tag")
-    .get(routes::repo_tag);
+    .at(&path("/:repo_name/commit/:commit"))
+    .get(routes::repo_commit);
+  app
+    .at(&path("/:repo_name/commit/:commit/raw"))
+    .get(routes::repo_commit_raw);
+  app
+    .at(&path("/:repo_name/archive/*archive"))
+    .get(routes::repo_archive);
 
+  app.at(&path("/:repo_name/refs")).get(routes::repo_refs);
+  app.at(&path("/:repo_name/refs/")).get(routes::repo_refs);
+  app.at(&path("/:repo_name/refs/:tag")).get(routes::repo_tag);
   app
-    .at("/:repo_name/log")
-    .get(routes::repo_log)
-    .at("/:repo_name/log/")
-    .get(routes::repo_log)
-    .at("/:repo_name/log/:ref")
-    .get(routes::repo_log) // ref is optional
-    .at("/:repo_name/log/:ref/")
-    .get(routes::repo_log) // ref is optional
-    .at(&format!(
-      "/{}/:repo_name/log/:ref/*object_name",
-      CONFIG.repos_root
-    ))
-    .get(routes::repo_log)
-    .at("/:repo_name/log.xml")
-    .get(routes::repo_log_feed)
-    .at(&format!(
-      "/{}/:repo_name/log/:ref/feed.xml",
-      CONFIG.repos_root
-    ))
-    .get(routes::repo_log_feed); // ref is optional
+    .at(&path("/:repo_name/refs.xml"))
+    .get(routes::repo_refs_feed);
+  app
+    .at(&path("/:repo_name/refs.atom"))
+    .get(routes::repo_refs_atom);
 
+  app.at(&path("/:repo_name/lists")).get(routes::repo_lists);
+  app.at(&path("/:repo_name/lists/")).get(routes::repo_lists);
   app
-    .at("/:repo_name/tree")
-    .get(routes::repo_file)
-    .at("/:repo_name/tree/")
-    .get(routes::repo_file)
-    .at("/:repo_name/tree/:ref")
-    .get(routes::repo_file) // ref is optional
-    .at("/:repo_name/tree/:ref/")
-    .get(routes::repo_file); // ref is optional
+    .at(&path("/:repo_name/lists/:message_id"))
+    .get(routes::repo_thread);
 
   app
-    .at("/:repo_name/tree/:ref/item/*object_name")
-    .get(routes::repo_file);
+    .at(&path("/:repo_name/patches"))
+    .get(routes::repo_patches);
+  app
+    .at(&path("/:repo_name/patches/:message_id"))
+    .get(routes::repo_series);
 
   app
-    .at("/:repo_name/tree/:ref/raw/*object_name")
+    .at(&path("/:repo_name/webhooks"))
+    .get(routes::repo_webhooks);
+
+  app.at(&path("/:repo_name/log")).get(routes::repo_log);
+  app.at(&path("/:repo_name/log/")).get(routes::repo_log);
+  // ref is optional
+  app.at(&path("/:repo_name/log/:ref")).get(routes::repo_log);
+  app.at(&path("/:repo_name/log/:ref/")).get(routes::repo_log);
+  app
+    .at(&path("/:repo_name/log/:ref/*object_name"))
+    .get(routes::repo_log);
+  app
+    .at(&path("/:repo_name/log.xml"))
+    .get(routes::repo_log_feed);
+  app
+    .at(&path("/:repo_name/log.atom"))
+    .get(routes::repo_log_atom);
+  // the history of a single file is selected with the "path" query parameter
+  app
+    .at(&path("/:repo_name/log/:ref/feed.xml"))
+    .get(routes::repo_log_feed);
+  app
+    .at(&path("/:repo_name/log/:ref/feed.atom"))
+    .get(routes::repo_log_atom);
+
+  app.at(&path("/:repo_name/tree")).get(routes::repo_file);
+  app.at(&path("/:repo_name/tree/")).get(routes::repo_file);
+  // ref is optional
+  app
+    .at(&path("/:repo_name/tree/:ref"))
+    .get(routes::repo_file);
+  app
+    .at(&path("/:repo_name/tree/:ref/"))
+    .get(routes::repo_file);
+  app
+    .at(&path("/:repo_name/tree/:ref/item/*object_name"))
+    .get(routes::repo_file);
+  app
+    .at(&path("/:repo_name/tree/:ref/raw/*object_name"))
     .get(routes::repo_file_raw);
 
   // static files
-  app.at("/static/*path").all(routes::static_resource);
+  app.at(&path("/static/*path")).all(routes::static_resource);
 
-  println!("running on http://localhost:{}", CONFIG.port);
-  app.listen(format!("0.0.0.0:{}", CONFIG.port)).await?;
-
-  Ok(())
+  app
 }
 
 pub(crate) mod route_prelude {
-  pub(crate) use crate::{filters, repo_from_request, StaticDir, CONFIG, SYNTAXES};
+  pub(crate) use crate::{
+    config, filters,
+    highlight::{self, HighlightedLines},
+    repo_from_request, StaticDir, SYNTAXES,
+  };
   pub(crate) use askama::Template;
   pub(crate) use git2::{Commit, Diff, DiffOptions, Reference, Repository, Signature, Tag};
   pub(crate) use lazy_static::lazy_static;
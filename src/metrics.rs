@@ -0,0 +1,127 @@
+use lazy_static::lazy_static;
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+/// Upper bounds of the latency histogram buckets in seconds
+const BUCKETS: [f64; 11] = [
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+  // the number of observations in each bucket, not cumulative
+  buckets: [u64; BUCKETS.len()],
+  count: u64,
+  sum: f64,
+}
+
+impl Histogram {
+  fn observe(&mut self, seconds: f64) {
+    if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+      self.buckets[i] += 1;
+    }
+    self.count += 1;
+    self.sum += seconds;
+  }
+}
+
+#[derive(Default)]
+struct Metrics {
+  /// request latency by route
+  latency: BTreeMap<&'static str, Histogram>,
+  /// number of responses by route and status code
+  responses: BTreeMap<(&'static str, u16), u64>,
+  /// number of times the refs of a repository were requested by git
+  clones: BTreeMap<String, u64>,
+}
+
+lazy_static! {
+  static ref METRICS: Mutex<Metrics> = Mutex::default();
+}
+
+/// Record a finished request to the route named `route`.
+pub(crate) fn record(route: &'static str, status: u16, latency: Duration) {
+  let mut metrics = METRICS.lock().unwrap();
+  metrics
+    .latency
+    .entry(route)
+    .or_default()
+    .observe(latency.as_secs_f64());
+  *metrics.responses.entry((route, status)).or_default() += 1;
+}
+
+/// Record that a repository was cloned or fetched from.
+pub(crate) fn record_clone(repo: &str) {
+  let mut metrics = METRICS.lock().unwrap();
+  *metrics.clones.entry(repo.to_string()).or_default() += 1;
+}
+
+/// Escape a label value for the Prometheus text format.
+fn label(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub(crate) fn render() -> String {
+  let metrics = METRICS.lock().unwrap();
+  let mut out = String::new();
+
+  out.push_str(
+    "# HELP agit_http_request_duration_seconds Time until the response headers were ready.\n",
+  );
+  out.push_str("# TYPE agit_http_request_duration_seconds histogram\n");
+  for (route, histogram) in &metrics.latency {
+    let mut cumulative = 0;
+    for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+      cumulative += count;
+      writeln!(
+        out,
+        "agit_http_request_duration_seconds_bucket{{route=\"{route}\",le=\"{bound}\"}} {cumulative}"
+      )
+      .unwrap();
+    }
+    writeln!(
+      out,
+      "agit_http_request_duration_seconds_bucket{{route=\"{route}\",le=\"+Inf\"}} {}",
+      histogram.count
+    )
+    .unwrap();
+    writeln!(
+      out,
+      "agit_http_request_duration_seconds_sum{{route=\"{route}\"}} {}",
+      histogram.sum
+    )
+    .unwrap();
+    writeln!(
+      out,
+      "agit_http_request_duration_seconds_count{{route=\"{route}\"}} {}",
+      histogram.count
+    )
+    .unwrap();
+  }
+
+  out.push_str("# HELP agit_http_responses_total Number of responses by route and status code.\n");
+  out.push_str("# TYPE agit_http_responses_total counter\n");
+  for ((route, status), count) in &metrics.responses {
+    writeln!(
+      out,
+      "agit_http_responses_total{{route=\"{route}\",status=\"{status}\"}} {count}"
+    )
+    .unwrap();
+  }
+
+  out.push_str("# HELP agit_git_clones_total Number of clones and fetches over HTTP.\n");
+  out.push_str("# TYPE agit_git_clones_total counter\n");
+  for (repo, count) in &metrics.clones {
+    writeln!(
+      out,
+      "agit_git_clones_total{{repo=\"{}\"}} {count}",
+      label(repo)
+    )
+    .unwrap();
+  }
+
+  out
+}
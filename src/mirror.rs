@@ -0,0 +1,554 @@
+//! Keeping mirrors of repositories that are hosted somewhere else up to date,
+//! and pushing repositories to their mirrors elsewhere.
+
+use crate::{watch, Config};
+use git2::{AutotagOption, Direction, FetchOptions, FetchPrune, RemoteCallbacks, Repository};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{hash_map::Entry, BTreeMap, HashMap},
+  fs,
+  io::{self, Read},
+  net::{TcpStream, ToSocketAddrs},
+  path::{Path, PathBuf},
+  process::{Command, Stdio},
+  sync::{mpsc::RecvTimeoutError, Mutex},
+  thread,
+  time::{Duration, Instant},
+};
+
+/// How often the mirrors are checked for whether they should be updated
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// The time between updates if `agit.mirrorInterval` is not set
+const DEFAULT_INTERVAL: i64 = 60 * 60;
+/// Everything is mirrored exactly, including deleted and rewritten refs.
+const REFSPECS: &[&str] = &["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"];
+/// How long to wait before pushing to a push mirror again after it failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How long to wait for the upstream server to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long fetching from upstream may take before it is aborted
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// How long pushing to a push mirror may take before git is killed, so one
+/// mirror that doesn't respond doesn't hold up the others
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+lazy_static::lazy_static! {
+  /// The git directories of the mirrors that are being updated, with when the
+  /// update was started as seconds since the epoch.
+  static ref UPDATING: Mutex<HashMap<PathBuf, i64>> = Mutex::default();
+}
+
+/// A repository that is a copy of the repository at `url`.
+pub(crate) struct Mirror {
+  pub(crate) url: String,
+  /// the number of seconds between updates
+  pub(crate) interval: i64,
+  pub(crate) status: Option<Status>,
+  /// when the update that is running now was started, because an upstream
+  /// that stops responding can hold it up for good
+  pub(crate) updating: Option<i64>,
+}
+
+/// A repository elsewhere that the branches and tags of a repository are
+/// pushed to.
+pub(crate) struct PushMirror {
+  pub(crate) url: String,
+  pub(crate) status: Option<Status>,
+}
+
+/// The result of the last update of a mirror.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Status {
+  /// when the last update was started, as seconds since the epoch
+  pub(crate) last_attempt: i64,
+  /// when the mirror was last updated successfully
+  pub(crate) last_success: Option<i64>,
+  /// why the last update failed
+  pub(crate) error: Option<String>,
+}
+
+/// `url` without credentials, for showing it to everyone.
+fn public_url(url: &str) -> String {
+  match tide::http::Url::parse(url) {
+    Ok(mut url) if url.has_host() => {
+      let _ = url.set_username("");
+      let _ = url.set_password(None);
+      url.to_string()
+    }
+    _ => url.to_string(),
+  }
+}
+
+fn format_time(seconds: i64) -> Option<String> {
+  crate::filters::format_datetime(git2::Time::new(seconds, 0), "%Y-%m-%d %H:%M:%S%z").ok()
+}
+
+/// When the last successful update of `status` was.
+fn updated(status: Option<&Status>) -> Option<String> {
+  format_time(status?.last_success?)
+}
+
+impl Mirror {
+  pub(crate) fn public_url(&self) -> String {
+    public_url(&self.url)
+  }
+
+  /// When the mirror was last updated successfully.
+  pub(crate) fn updated(&self) -> Option<String> {
+    updated(self.status.as_ref())
+  }
+
+  /// When the update that is running now was started.
+  pub(crate) fn updating(&self) -> Option<String> {
+    format_time(self.updating?)
+  }
+
+  pub(crate) fn error(&self) -> Option<&str> {
+    self.status.as_ref()?.error.as_deref()
+  }
+}
+
+impl PushMirror {
+  pub(crate) fn public_url(&self) -> String {
+    public_url(&self.url)
+  }
+
+  /// When the mirror was last pushed to successfully.
+  pub(crate) fn updated(&self) -> Option<String> {
+    updated(self.status.as_ref())
+  }
+
+  pub(crate) fn error(&self) -> Option<&str> {
+    self.status.as_ref()?.error.as_deref()
+  }
+}
+
+fn status_path(repo: &Repository) -> PathBuf {
+  repo.path().join("agit").join("mirror-status")
+}
+
+/// The upstream configured with `agit.mirror` in the git config of `repo`, if
+/// it is a mirror.
+pub(crate) fn mirror(repo: &Repository) -> Option<Mirror> {
+  let config = repo.config().ok()?;
+  let url = config
+    .get_string("agit.mirror")
+    .ok()
+    .filter(|url| !url.is_empty())?;
+  let interval = config
+    .get_i64("agit.mirrorInterval")
+    .unwrap_or(DEFAULT_INTERVAL);
+  let status = fs::read(status_path(repo))
+    .ok()
+    .and_then(|status| serde_json::from_slice(&status).ok());
+  let updating = UPDATING.lock().unwrap().get(repo.path()).copied();
+  Some(Mirror {
+    url,
+    interval,
+    status,
+    updating,
+  })
+}
+
+/// Update the mirrors in the background when they are due.
+///
+/// Every mirror is updated in its own thread, so a slow or unreachable upstream
+/// does not hold up the other mirrors.
+pub(crate) fn start() {
+  thread::spawn(|| loop {
+    let now = chrono::Utc::now().timestamp();
+    for repo in crate::exported_repos() {
+      let mirror = match mirror(&repo) {
+        Some(mirror) => mirror,
+        None => continue,
+      };
+      let due = match &mirror.status {
+        Some(status) => now - status.last_attempt >= mirror.interval,
+        None => true,
+      };
+      let git_dir = repo.path().to_path_buf();
+      let started = due && {
+        let mut updating = UPDATING.lock().unwrap();
+        match updating.entry(git_dir.clone()) {
+          Entry::Occupied(_) => false,
+          Entry::Vacant(entry) => {
+            entry.insert(now);
+            true
+          }
+        }
+      };
+      if started {
+        thread::spawn(move || {
+          if let Err(e) = sync(&repo, &mirror) {
+            tide::log::warn!(
+              "could not record the mirror status of {:?}: {}",
+              repo.path(),
+              e
+            );
+          }
+          UPDATING.lock().unwrap().remove(&git_dir);
+        });
+      }
+    }
+    thread::sleep(CHECK_INTERVAL);
+  });
+}
+
+/// Check that the server of `url` accepts connections within
+/// `CONNECT_TIMEOUT`, because libgit2 waits for as long as the system does.
+fn check_connection(url: &str) -> Result<(), git2::Error> {
+  let url = match tide::http::Url::parse(url) {
+    Ok(url) if url.has_host() => url,
+    // local paths
+    _ => return Ok(()),
+  };
+  let port = match (url.port(), url.scheme()) {
+    (Some(port), _) => port,
+    (None, "git") => 9418,
+    (None, "ssh") => 22,
+    (None, "http") => 80,
+    (None, "https") => 443,
+    _ => return Ok(()),
+  };
+  let host = url.host_str().unwrap_or_default();
+  let host = host.trim_start_matches('[').trim_end_matches(']');
+  let addresses = (host, port)
+    .to_socket_addrs()
+    .map_err(|e| git2::Error::from_str(&format!("could not resolve {}: {}", host, e)))?;
+  let mut error = None;
+  for address in addresses {
+    match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+      Ok(_) => return Ok(()),
+      Err(e) => error = Some(e),
+    }
+  }
+  Err(git2::Error::from_str(&match error {
+    Some(e) => format!("could not connect to {}: {}", host, e),
+    None => format!("{} has no addresses", host),
+  }))
+}
+
+/// Callbacks that abort fetching after `deadline`.
+fn callbacks(deadline: Instant) -> RemoteCallbacks<'static> {
+  let mut callbacks = RemoteCallbacks::new();
+  callbacks
+    .transfer_progress(move |_| Instant::now() < deadline)
+    .sideband_progress(move |_| Instant::now() < deadline);
+  callbacks
+}
+
+/// Update `repo` from its upstream and record the result.
+pub(crate) fn sync(repo: &Repository, mirror: &Mirror) -> io::Result<()> {
+  let last_attempt = chrono::Utc::now().timestamp();
+  let result = fetch(repo, &mirror.url);
+  if let Err(e) = &result {
+    tide::log::warn!(
+      "could not update the mirror {:?} from {}: {}",
+      repo.path(),
+      mirror.public_url(),
+      e
+    );
+  }
+
+  let status = Status {
+    last_attempt,
+    last_success: match result {
+      Ok(()) => Some(last_attempt),
+      Err(_) => mirror
+        .status
+        .as_ref()
+        .and_then(|status| status.last_success),
+    },
+    error: result.err().map(|e| e.message().to_string()),
+  };
+  let path = status_path(repo);
+  fs::create_dir_all(path.parent().unwrap())?;
+  let tmp = path.with_extension("tmp");
+  fs::write(&tmp, serde_json::to_vec(&status)?)?;
+  fs::rename(tmp, path)
+}
+
+/// Fetch all branches and tags from `url`, and point HEAD to the same branch
+/// as upstream.
+fn fetch(repo: &Repository, url: &str) -> Result<(), git2::Error> {
+  let before = crate::refs::snapshot(repo)?;
+
+  check_connection(url)?;
+  let deadline = Instant::now() + TRANSFER_TIMEOUT;
+  let mut remote = repo.remote_anonymous(url)?;
+  let head = {
+    let connection = remote.connect_auth(Direction::Fetch, Some(callbacks(deadline)), None)?;
+    connection
+      .default_branch()
+      .ok()
+      .and_then(|branch| branch.as_str().map(String::from))
+  };
+  let mut options = FetchOptions::new();
+  options
+    .prune(FetchPrune::On)
+    .download_tags(AutotagOption::None)
+    .remote_callbacks(callbacks(deadline));
+  remote
+    .fetch(REFSPECS, Some(&mut options), Some("agit: update mirror"))
+    .map_err(|e| {
+      if Instant::now() >= deadline {
+        git2::Error::from_str(&format!(
+          "fetching took longer than {} minutes",
+          TRANSFER_TIMEOUT.as_secs() / 60
+        ))
+      } else {
+        e
+      }
+    })?;
+
+  if let Some(head) = head {
+    if repo.find_reference(&head).is_ok() {
+      repo.set_head(&head)?;
+    }
+  }
+
+  // like after a push, so the repository can be cloned over "dumb http"
+  let after = crate::refs::snapshot(repo)?;
+  let updated = crate::refs::updates(&before, &after);
+  let hook = repo.path().join("hooks").join("post-update");
+  if !updated.is_empty() && hook.is_file() {
+    let status = Command::new(&hook)
+      .args(updated.iter().map(|update| &update.name))
+      .current_dir(repo.path())
+      .env("GIT_DIR", ".")
+      .status();
+    if !matches!(status, Ok(status) if status.success()) {
+      tide::log::warn!("the post-update hook of {:?} failed", repo.path());
+    }
+  }
+  Ok(())
+}
+
+fn push_status_path(repo: &Repository) -> PathBuf {
+  repo.path().join("agit").join("push-mirrors")
+}
+
+/// The statuses of the push mirrors of `repo`, by their URL.
+fn push_statuses(repo: &Repository) -> BTreeMap<String, Status> {
+  fs::read(push_status_path(repo))
+    .ok()
+    .and_then(|statuses| serde_json::from_slice(&statuses).ok())
+    .unwrap_or_default()
+}
+
+/// The push mirrors configured with `agit.pushMirror` in the git config of
+/// `repo`. The option can be given several times.
+pub(crate) fn push_mirrors(repo: &Repository) -> Vec<PushMirror> {
+  let config = match repo.config() {
+    Ok(config) => config,
+    Err(_) => return Vec::new(),
+  };
+  let mut statuses = push_statuses(repo);
+  let mut mirrors = Vec::new();
+  if let Ok(mut entries) = config.multivar("agit.pushMirror", None) {
+    while let Some(Ok(entry)) = entries.next() {
+      if let Some(url) = entry.value().map(str::trim).filter(|url| !url.is_empty()) {
+        mirrors.push(PushMirror {
+          url: url.to_string(),
+          status: statuses.remove(url),
+        });
+      }
+    }
+  }
+  mirrors
+}
+
+/// Push the repositories to their push mirrors whenever their refs change, and
+/// retry failed pushes now and then.
+pub(crate) fn start_pushing() {
+  let changes = watch::subscribe();
+  thread::spawn(move || {
+    // catch up with pushes while agit was not running
+    for repo in crate::exported_repos() {
+      push_all(&repo, |_| true);
+    }
+    loop {
+      match changes.recv_timeout(RETRY_INTERVAL) {
+        Ok(change) => {
+          if let Ok(repo) = Repository::open(&change.path) {
+            push_all(&repo, |_| true);
+          }
+        }
+        Err(RecvTimeoutError::Timeout) => {
+          for repo in crate::exported_repos() {
+            push_all(&repo, |mirror| mirror.error().is_some());
+          }
+        }
+        Err(RecvTimeoutError::Disconnected) => return,
+      }
+    }
+  });
+}
+
+/// Push `repo` to those of its push mirrors that `filter` selects, and record
+/// the results.
+fn push_all(repo: &Repository, filter: impl Fn(&PushMirror) -> bool) {
+  let mirrors = push_mirrors(repo);
+  if !mirrors.iter().any(&filter) {
+    return;
+  }
+
+  let mut statuses = push_statuses(repo);
+  for mirror in mirrors.iter().filter(|mirror| filter(mirror)) {
+    let last_attempt = chrono::Utc::now().timestamp();
+    let result = push(repo.path(), &mirror.url);
+    if let Err(e) = &result {
+      tide::log::warn!(
+        "could not push {:?} to {}: {}",
+        repo.path(),
+        mirror.public_url(),
+        e
+      );
+    }
+    let status = Status {
+      last_attempt,
+      last_success: match result {
+        Ok(()) => Some(last_attempt),
+        Err(_) => mirror
+          .status
+          .as_ref()
+          .and_then(|status| status.last_success),
+      },
+      error: result.err(),
+    };
+    statuses.insert(mirror.url.clone(), status);
+  }
+  // forget about mirrors that were removed
+  statuses.retain(|url, _| mirrors.iter().any(|mirror| &mirror.url == url));
+
+  let path = push_status_path(repo);
+  let result = fs::create_dir_all(path.parent().unwrap()).and_then(|()| {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(&statuses)?)?;
+    fs::rename(tmp, &path)
+  });
+  if let Err(e) = result {
+    tide::log::warn!(
+      "could not record the push mirror status of {:?}: {}",
+      repo.path(),
+      e
+    );
+  }
+}
+
+/// The origin of `url` and the token from `push_tokens` for its host, which
+/// is only sent over https.
+fn push_token<'a>(config: &'a Config, url: &str) -> Result<Option<(String, &'a str)>, String> {
+  let url = match tide::http::Url::parse(url) {
+    Ok(url) if url.has_host() => url,
+    // ssh URLs like "git@github.com:example/agit.git" and local paths
+    _ => return Ok(None),
+  };
+  let host = url.host_str().unwrap_or_default();
+  let token = url
+    .port()
+    .and_then(|port| config.push_tokens.get(&format!("{}:{}", host, port)))
+    .or_else(|| config.push_tokens.get(host))
+    .filter(|token| !token.is_empty());
+  match (url.scheme(), token) {
+    (_, None) => Ok(None),
+    ("https", Some(token)) => Ok(Some((url.origin().ascii_serialization(), token))),
+    (scheme, Some(_)) => Err(format!(
+      "refusing to send the push token for {} over {}, use an https URL",
+      host, scheme
+    )),
+  }
+}
+
+/// Push all branches and tags in the repository at `git_dir` to `url`, deleting
+/// the ones that don't exist here any more.
+///
+/// The git binary is used instead of libgit2 for its support of ssh, including
+/// checking host keys against `known_hosts`.
+fn push(git_dir: &Path, url: &str) -> Result<(), String> {
+  let config = crate::config();
+  let mut command = Command::new("git");
+  command
+    .env("GIT_DIR", git_dir)
+    // fail instead of asking for credentials
+    .env("GIT_TERMINAL_PROMPT", "0")
+    .stdin(Stdio::null());
+  if !config.push_ssh_key.is_empty() {
+    command.env(
+      "GIT_SSH_COMMAND",
+      format!(
+        "ssh -i '{}' -o IdentitiesOnly=yes -o BatchMode=yes",
+        config.push_ssh_key.replace('\'', "'\\''")
+      ),
+    );
+  }
+  if let Some((origin, token)) = push_token(&config, url)? {
+    // the token is passed in the environment, so it can't be seen in the list
+    // of processes, and the helper only answers for the host of the mirror,
+    // also when the server redirects somewhere else
+    command
+      .env("AGIT_PUSH_TOKEN", token)
+      .args(["-c", "credential.helper="])
+      .arg("-c")
+      .arg(format!(
+        "credential.{}.helper=!f() {{ echo username=agit; echo \"password=$AGIT_PUSH_TOKEN\"; }}; f",
+        origin
+      ));
+  }
+  // only branches and tags, like what is mirrored from upstream
+  let mut child = command
+    .args(["push", "--porcelain", "--force", "--prune", "--", url])
+    .args(["refs/heads/*:refs/heads/*", "refs/tags/*:refs/tags/*"])
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|e| format!("could not run git: {}", e))?;
+  // read while git runs, so it can't block on a full pipe
+  let mut stderr = child.stderr.take().unwrap();
+  let reader = thread::spawn(move || {
+    let mut output = Vec::new();
+    let _ = stderr.read_to_end(&mut output);
+    output
+  });
+
+  let deadline = Instant::now() + PUSH_TIMEOUT;
+  let status = loop {
+    match child.try_wait() {
+      Ok(Some(status)) => break status,
+      Ok(None) if Instant::now() > deadline => {
+        // ssh or the remote helper exit when git is gone
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(format!(
+          "git push took longer than {} minutes",
+          PUSH_TIMEOUT.as_secs() / 60
+        ));
+      }
+      Ok(None) => thread::sleep(Duration::from_millis(100)),
+      Err(e) => return Err(format!("could not run git: {}", e)),
+    }
+  };
+  let stderr = reader.join().unwrap_or_default();
+
+  if status.success() {
+    Ok(())
+  } else {
+    // what ssh or the server said comes before the first fatal error, and only
+    // general advice after it
+    let stderr = String::from_utf8_lossy(&stderr);
+    let lines = stderr
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty())
+      .collect::<Vec<_>>();
+    let end = lines
+      .iter()
+      .position(|line| line.starts_with("fatal:"))
+      .map_or(lines.len(), |fatal| fatal + 1);
+    if end == 0 {
+      Err(format!("git push failed with {}", status))
+    } else {
+      Err(lines[..end].join(" "))
+    }
+  }
+}
@@ -0,0 +1,574 @@
+//! Emails about pushed commits, sent with a sendmail binary or to an SMTP
+//! server.
+
+use crate::{
+  config,
+  refs::{self, RefSnapshot, RefUpdate},
+  watch, Config,
+};
+use git2::{Oid, Repository};
+use std::{
+  error::Error,
+  fmt::Write as _,
+  io::{self, BufRead, BufReader, Write},
+  net::TcpStream,
+  process::{Command, Stdio},
+  thread,
+  time::Duration,
+};
+
+/// The maximum number of commits listed in one email
+const MAX_COMMITS: usize = 50;
+
+/// Check the repositories whenever their refs change.
+pub(crate) fn start() {
+  let changes = watch::subscribe();
+  thread::spawn(move || {
+    // catch up with pushes while agit was not running
+    for repo in crate::exported_repos() {
+      match refs::snapshot(&repo) {
+        Ok(new) => run(&repo, None, new),
+        Err(e) => tide::log::warn!("can't read the refs of {:?}: {}", repo.path(), e),
+      }
+    }
+    for change in changes {
+      if let Ok(repo) = Repository::open(&change.path) {
+        run(&repo, Some(&change.old), change.new.clone());
+      }
+    }
+  });
+}
+
+fn run(repo: &Repository, old: Option<&RefSnapshot>, new: RefSnapshot) {
+  if let Err(e) = check(repo, old, new) {
+    tide::log::warn!("could not send notification for {:?}: {}", repo.path(), e);
+  }
+}
+
+/// The addresses configured with `agit.notify` in the git config of the
+/// repository. The option can be given several times, or contain a comma
+/// separated list.
+fn recipients(repo: &Repository) -> Vec<String> {
+  let config = match repo.config() {
+    Ok(config) => config,
+    Err(_) => return Vec::new(),
+  };
+  let mut recipients = Vec::new();
+  if let Ok(mut entries) = config.multivar("agit.notify", None) {
+    while let Some(Ok(entry)) = entries.next() {
+      if let Some(value) = entry.value() {
+        recipients.extend(
+          value
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(String::from),
+        );
+      }
+    }
+  }
+  recipients
+}
+
+/// Send an email if the refs of `repo` changed since the last email.
+///
+/// The refs the last email was sent for are stored in the git directory. If
+/// there are none yet, the refs before the change are used if it is known,
+/// like after notifications were enabled while agit was running. Otherwise the
+/// refs are only stored, so enabling notifications does not send an email
+/// about the whole history.
+fn check(
+  repo: &Repository,
+  before: Option<&RefSnapshot>,
+  new: RefSnapshot,
+) -> Result<(), Box<dyn Error>> {
+  let config = config();
+  let recipients = recipients(repo);
+  if recipients.is_empty() || (config.sendmail.is_empty() && config.smtp_host.is_empty()) {
+    return Ok(());
+  }
+
+  let path = repo.path().join("agit").join("notified-refs");
+  let old = match (refs::load(&path)?, before) {
+    (Some(old), _) => old,
+    (None, Some(before)) => before.clone(),
+    (None, None) => return Ok(refs::store(&path, &new)?),
+  };
+  let updates = refs::updates(&old, &new);
+  if updates.is_empty() {
+    return Ok(());
+  }
+
+  let message = message(&config, repo, &old, &updates, &recipients)?;
+  send(&config, &recipients, &message)?;
+  // only after sending, so failed emails are tried again
+  refs::store(&path, &new)?;
+  Ok(())
+}
+
+/// Describe a change of a ref in a few words.
+fn describe(repo: &Repository, update: &RefUpdate) -> String {
+  let kind = if update.is_tag() { "tag" } else { "branch" };
+  let short = |oid: Oid| {
+    repo
+      .find_object(oid, None)
+      .ok()
+      .and_then(|obj| obj.short_id().ok()?.as_str().map(String::from))
+      .unwrap_or_else(|| oid.to_string())
+  };
+  match (update.old, update.new) {
+    (None, Some(new)) => format!("{} {} created at {}", kind, update.short_name(), short(new)),
+    (Some(old), None) => format!(
+      "{} {} deleted (was {})",
+      kind,
+      update.short_name(),
+      short(old)
+    ),
+    (Some(old), Some(new)) => {
+      let forced = !repo.graph_descendant_of(new, old).unwrap_or(false);
+      format!(
+        "{} {} updated: {}..{}{}",
+        kind,
+        update.short_name(),
+        short(old),
+        short(new),
+        if forced { " (forced update)" } else { "" }
+      )
+    }
+    (None, None) => unreachable!("a ref that never existed was updated"),
+  }
+}
+
+/// Encode a header value as described in RFC 2047 if it is not plain ASCII.
+fn encode_header(value: &str) -> String {
+  if value.is_ascii() {
+    return value.to_string();
+  }
+  let mut encoded = "=?UTF-8?Q?".to_string();
+  for byte in value.bytes() {
+    match byte {
+      b' ' => encoded.push('_'),
+      b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b',' | b'-' | b':' => {
+        encoded.push(byte as char)
+      }
+      _ => write!(encoded, "={:02X}", byte).unwrap(),
+    }
+  }
+  encoded + "?="
+}
+
+/// Build the email for the updates of the refs of `repo`.
+fn message(
+  config: &Config,
+  repo: &Repository,
+  old: &RefSnapshot,
+  updates: &[RefUpdate],
+  recipients: &[String],
+) -> Result<String, git2::Error> {
+  let name = crate::filters::repo_name(repo).unwrap_or_default();
+  let base_url = if config.base_url.is_empty() {
+    "http://localhost"
+  } else {
+    config.base_url.trim_end_matches('/')
+  };
+
+  // the commits that were not in the repository before
+  let mut revwalk = repo.revwalk()?;
+  revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+  for new in updates.iter().filter_map(|update| update.new) {
+    if let Ok(commit) = repo.find_object(new, None)?.peel_to_commit() {
+      revwalk.push(commit.id())?;
+    }
+  }
+  for &oid in old.values() {
+    if let Ok(commit) = repo
+      .find_object(oid, None)
+      .and_then(|obj| obj.peel_to_commit())
+    {
+      // the commit might not exist any more, which does not matter
+      let _ = revwalk.hide(commit.id());
+    }
+  }
+  let commits = revwalk
+    .filter_map(|oid| repo.find_commit(oid.ok()?).ok())
+    .collect::<Vec<_>>();
+
+  let subject = match (updates, commits.len()) {
+    ([update], 0) => format!("[{}] {}", name, describe(repo, update)),
+    ([update], 1) => format!(
+      "[{}] {}: {}",
+      name,
+      update.short_name(),
+      commits[0].summary().unwrap_or_default()
+    ),
+    ([update], n) => format!("[{}] {}: {} new commits", name, update.short_name(), n),
+    (_, n) => format!(
+      "[{}] {} refs updated, {} new commit{}",
+      name,
+      updates.len(),
+      n,
+      if n == 1 { "" } else { "s" }
+    ),
+  };
+
+  let mut body = String::new();
+  for update in updates {
+    writeln!(body, "{}", describe(repo, update)).unwrap();
+  }
+
+  // the changes of the updated branches as a whole
+  for update in updates.iter().filter(|update| !update.is_tag()) {
+    if let (Some(old), Some(new)) = (update.old, update.new) {
+      let old_tree = repo.find_commit(old).and_then(|commit| commit.tree());
+      let new_tree = repo.find_commit(new).and_then(|commit| commit.tree());
+      if let (Ok(old_tree), Ok(new_tree)) = (old_tree, new_tree) {
+        let stats = repo
+          .diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?
+          .stats()?
+          .to_buf(git2::DiffStatsFormat::FULL, 72)?;
+        writeln!(
+          body,
+          "\n{}:\n{}",
+          update.short_name(),
+          stats.as_str().unwrap_or_default()
+        )
+        .unwrap();
+      }
+    }
+  }
+
+  if !commits.is_empty() {
+    writeln!(body, "\nNew commits:").unwrap();
+  }
+  for commit in commits.iter().take(MAX_COMMITS) {
+    let author = commit.author();
+    writeln!(
+      body,
+      "\n{} {}\nAuthor: {} <{}>\nDate:   {}\n{}/{}/commit/{}",
+      crate::filters::short_id(commit).unwrap(),
+      commit.summary().unwrap_or_default(),
+      author.name().unwrap_or_default(),
+      author.email().unwrap_or_default(),
+      crate::filters::format_datetime(commit.time(), "%c %z").unwrap(),
+      base_url,
+      percent_encoding::utf8_percent_encode(name, percent_encoding::NON_ALPHANUMERIC),
+      commit.id(),
+    )
+    .unwrap();
+  }
+  if commits.len() > MAX_COMMITS {
+    writeln!(body, "\n... and {} more", commits.len() - MAX_COMMITS).unwrap();
+  }
+
+  let now = chrono::Local::now();
+  // the time can only be out of range after the year 2262
+  let unique = now.timestamp_nanos_opt().unwrap_or_else(|| now.timestamp());
+  Ok(format!(
+    "From: {}\nTo: {}\nSubject: {}\nDate: {}\nMessage-ID: <{}.{}@agit>\nMIME-Version: 1.0\nContent-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 8bit\nX-Git-Repo: {}\n\n{}",
+    config.notify_from,
+    recipients.join(", "),
+    encode_header(&subject),
+    now.to_rfc2822(),
+    unique,
+    std::process::id(),
+    name,
+    body
+  ))
+}
+
+/// The address in an address that may contain a name, like `Name <address>`.
+fn bare_address(address: &str) -> &str {
+  match address.rsplit_once('<') {
+    Some((_, rest)) => rest.trim_end_matches('>').trim(),
+    None => address.trim(),
+  }
+}
+
+fn send(config: &Config, recipients: &[String], message: &str) -> io::Result<()> {
+  if !config.sendmail.is_empty() {
+    let mut child = Command::new(&config.sendmail)
+      .arg("-oi")
+      .arg("-f")
+      .arg(bare_address(&config.notify_from))
+      .arg("--")
+      .args(recipients.iter().map(|address| bare_address(address)))
+      .stdin(Stdio::piped())
+      .spawn()?;
+    child.stdin.take().unwrap().write_all(message.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+      return Err(io::Error::other(format!(
+        "{} exited with {}",
+        config.sendmail, status
+      )));
+    }
+    Ok(())
+  } else {
+    smtp(config, recipients, message)
+  }
+}
+
+/// Send `message` to the SMTP server `smtp_host` without encryption or
+/// authentication, as to a relay on the same host or network.
+fn smtp(config: &Config, recipients: &[String], message: &str) -> io::Result<()> {
+  let stream = TcpStream::connect(&config.smtp_host)?;
+  stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut writer = stream;
+
+  // read a possibly multiline reply and check its status code
+  let mut reply = |expected: u8| -> io::Result<()> {
+    loop {
+      let mut line = String::new();
+      if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+      }
+      // "250-" continues, "250 " ends the reply
+      if line.as_bytes().get(3) != Some(&b'-') {
+        return if line.as_bytes().first() == Some(&expected) {
+          Ok(())
+        } else {
+          Err(io::Error::other(format!(
+            "SMTP server replied {}",
+            line.trim_end()
+          )))
+        };
+      }
+    }
+  };
+
+  reply(b'2')?;
+  writer.write_all(b"HELO localhost\r\n")?;
+  reply(b'2')?;
+  write!(
+    writer,
+    "MAIL FROM:<{}>\r\n",
+    bare_address(&config.notify_from)
+  )?;
+  reply(b'2')?;
+  for recipient in recipients {
+    write!(writer, "RCPT TO:<{}>\r\n", bare_address(recipient))?;
+    reply(b'2')?;
+  }
+  writer.write_all(b"DATA\r\n")?;
+  reply(b'3')?;
+  for line in message.lines() {
+    // lines starting with a dot are escaped by doubling it
+    if line.starts_with('.') {
+      writer.write_all(b".")?;
+    }
+    write!(writer, "{}\r\n", line)?;
+  }
+  writer.write_all(b".\r\n")?;
+  reply(b'2')?;
+  writer.write_all(b"QUIT\r\n")?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use git2::Signature;
+  use std::{fs, net::TcpListener, path::Path};
+
+  fn config(extra: &str) -> Config {
+    toml::from_str(&format!(
+      "notify_from = \"agit <agit@example.com>\"\nbase_url = \"https://git.example.com/\"\n{}",
+      extra
+    ))
+    .unwrap()
+  }
+
+  /// Commit a file with `content` on top of HEAD.
+  fn commit(repo: &Repository, summary: &str, content: &str) -> Oid {
+    let signature = Signature::now("Alice Example", "alice@example.com").unwrap();
+    let mut index = repo.index().unwrap();
+    fs::write(repo.workdir().unwrap().join("file.txt"), content).unwrap();
+    index.add_path(Path::new("file.txt")).unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let parents = parent.iter().collect::<Vec<_>>();
+    repo
+      .commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        summary,
+        &tree,
+        &parents,
+      )
+      .unwrap()
+  }
+
+  /// A push of two commits to master and a new tag.
+  fn push() -> (tempfile::TempDir, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init(dir.path().join("project")).unwrap();
+    repo.set_head("refs/heads/master").unwrap();
+    commit(&repo, "First", "one\n");
+    let old = refs::snapshot(&repo).unwrap();
+    commit(&repo, "Second", "one\ntwo\n");
+    let third = commit(&repo, "Third", "one\ntwo\nthree\n");
+    let object = repo.find_object(third, None).unwrap();
+    repo.tag_lightweight("v1.0", &object, false).unwrap();
+    let new = refs::snapshot(&repo).unwrap();
+
+    let message = message(
+      &config(""),
+      &repo,
+      &old,
+      &refs::updates(&old, &new),
+      &["dev@example.com".into(), "Bob <bob@example.com>".into()],
+    )
+    .unwrap();
+    (dir, message)
+  }
+
+  #[test]
+  fn message_headers_and_body() {
+    let (_dir, message) = push();
+    let (headers, body) = message.split_once("\n\n").unwrap();
+    let header = |name: &str| {
+      headers
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+        .unwrap_or_else(|| panic!("no {} header in {:?}", name, headers))
+    };
+
+    assert_eq!(header("From"), "agit <agit@example.com>");
+    assert_eq!(header("To"), "dev@example.com, Bob <bob@example.com>");
+    assert_eq!(header("Subject"), "[project] 2 refs updated, 2 new commits");
+    assert_eq!(header("Content-Type"), "text/plain; charset=utf-8");
+    assert_eq!(header("X-Git-Repo"), "project");
+    assert!(header("Message-ID").ends_with("@agit>"));
+    chrono::DateTime::parse_from_rfc2822(header("Date")).unwrap();
+
+    assert!(body.contains("branch master updated: "));
+    assert!(body.contains("tag v1.0 created at "));
+    assert!(body.contains("file.txt | 2 ++"));
+    assert!(body.contains("Author: Alice Example <alice@example.com>"));
+    assert!(body.contains("https://git.example.com/project/commit/"));
+    // the newest commit first
+    assert!(body.find("Third").unwrap() < body.find("Second").unwrap());
+    assert!(!body.contains("First"));
+  }
+
+  #[test]
+  fn non_ascii_subject_is_encoded() {
+    assert_eq!(encode_header("plain"), "plain");
+    assert_eq!(encode_header("caf\u{e9} bar"), "=?UTF-8?Q?caf=C3=A9_bar?=");
+  }
+
+  #[test]
+  fn smtp_sends_envelope_and_message() {
+    let (_dir, message) = push();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // a stand-in SMTP server that records the commands it gets
+    let server = thread::spawn(move || {
+      let (stream, _) = listener.accept().unwrap();
+      let mut reader = BufReader::new(stream.try_clone().unwrap());
+      let mut writer = stream;
+      let mut commands = Vec::new();
+      let mut data = String::new();
+      writer.write_all(b"220 stand-in ready\r\n").unwrap();
+      loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 {
+          break;
+        }
+        let line = line.trim_end().to_string();
+        if line == "DATA" {
+          writer.write_all(b"354 go ahead\r\n").unwrap();
+          loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == ".\r\n" {
+              break;
+            }
+            data.push_str(&line);
+          }
+          writer.write_all(b"250 queued\r\n").unwrap();
+        } else if line == "QUIT" {
+          writer.write_all(b"221 bye\r\n").unwrap();
+          break;
+        } else {
+          // the greeting is a multiline reply
+          writer.write_all(b"250-stand-in\r\n250 ok\r\n").unwrap();
+        }
+        commands.push(line);
+      }
+      (commands, data)
+    });
+
+    let recipients = ["dev@example.com".into(), "Bob <bob@example.com>".into()];
+    send(
+      &config(&format!("smtp_host = \"{}\"", address)),
+      &recipients,
+      &message,
+    )
+    .unwrap();
+    let (commands, data) = server.join().unwrap();
+
+    assert_eq!(
+      commands,
+      [
+        "HELO localhost",
+        "MAIL FROM:<agit@example.com>",
+        "RCPT TO:<dev@example.com>",
+        "RCPT TO:<bob@example.com>",
+        "DATA",
+      ]
+    );
+    assert_eq!(data, message.replace('\n', "\r\n"));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn sendmail_gets_recipients_and_message() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (dir, message) = push();
+    // a stand-in sendmail that saves its arguments and input
+    let sendmail = dir.path().join("sendmail");
+    let output = dir.path().join("output");
+    fs::write(
+      &sendmail,
+      format!(
+        "#!/bin/sh\necho \"$@\" > '{0}.args'\ncat > '{0}.message'\n",
+        output.display()
+      ),
+    )
+    .unwrap();
+    fs::set_permissions(&sendmail, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let recipients = ["dev@example.com".into(), "Bob <bob@example.com>".into()];
+    send(
+      &config(&format!("sendmail = \"{}\"", sendmail.display())),
+      &recipients,
+      &message,
+    )
+    .unwrap();
+
+    assert_eq!(
+      fs::read_to_string(output.with_extension("args")).unwrap(),
+      "-oi -f agit@example.com -- dev@example.com bob@example.com\n"
+    );
+    assert_eq!(
+      fs::read_to_string(output.with_extension("message")).unwrap(),
+      message
+    );
+  }
+
+  #[test]
+  fn failing_sendmail_is_an_error() {
+    let (_dir, message) = push();
+    let result = send(
+      &config("sendmail = \"false\""),
+      &["dev@example.com".into()],
+      &message,
+    );
+    assert!(result.is_err());
+  }
+}
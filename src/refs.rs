@@ -0,0 +1,102 @@
+//! Snapshots of the refs of a repository, to find out what changed between
+//! two points in time.
+
+use git2::{Oid, Repository};
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+/// The commit or tag every ref points to, by the full name of the ref.
+pub(crate) type RefSnapshot = BTreeMap<String, Oid>;
+
+/// A ref that was created, updated or deleted.
+pub(crate) struct RefUpdate {
+  pub(crate) name: String,
+  /// `None` if the ref was created
+  pub(crate) old: Option<Oid>,
+  /// `None` if the ref was deleted
+  pub(crate) new: Option<Oid>,
+}
+
+impl RefUpdate {
+  /// The name of the ref without the `refs/heads/` or `refs/tags/` prefix.
+  pub(crate) fn short_name(&self) -> &str {
+    self
+      .name
+      .strip_prefix("refs/heads/")
+      .or_else(|| self.name.strip_prefix("refs/tags/"))
+      .unwrap_or(&self.name)
+  }
+
+  pub(crate) fn is_tag(&self) -> bool {
+    self.name.starts_with("refs/tags/")
+  }
+}
+
+/// The current branches and tags of `repo`. Symbolic refs are left out.
+pub(crate) fn snapshot(repo: &Repository) -> Result<RefSnapshot, git2::Error> {
+  let mut snapshot = RefSnapshot::new();
+  for reference in repo.references()? {
+    let reference = reference?;
+    if let (Some(name), Some(target)) = (reference.name(), reference.target()) {
+      if name.starts_with("refs/heads/") || name.starts_with("refs/tags/") {
+        snapshot.insert(name.to_string(), target);
+      }
+    }
+  }
+  Ok(snapshot)
+}
+
+/// The refs that are different in `new` compared to `old`.
+pub(crate) fn updates(old: &RefSnapshot, new: &RefSnapshot) -> Vec<RefUpdate> {
+  let mut updates = Vec::new();
+  for (name, &oid) in new {
+    if old.get(name) != Some(&oid) {
+      updates.push(RefUpdate {
+        name: name.clone(),
+        old: old.get(name).copied(),
+        new: Some(oid),
+      });
+    }
+  }
+  for (name, &oid) in old {
+    if !new.contains_key(name) {
+      updates.push(RefUpdate {
+        name: name.clone(),
+        old: Some(oid),
+        new: None,
+      });
+    }
+  }
+  updates
+}
+
+/// Read a snapshot written by [`store`], or `None` if there is none yet.
+pub(crate) fn load(path: &Path) -> io::Result<Option<RefSnapshot>> {
+  let text = match fs::read_to_string(path) {
+    Ok(text) => text,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e),
+  };
+  // the same format as packed-refs, one "<oid> <name>" per line
+  let snapshot = text
+    .lines()
+    .filter_map(|line| {
+      let (oid, name) = line.split_once(' ')?;
+      Some((name.to_string(), Oid::from_str(oid).ok()?))
+    })
+    .collect();
+  Ok(Some(snapshot))
+}
+
+/// Write `snapshot` to `path`, replacing it atomically.
+pub(crate) fn store(path: &Path, snapshot: &RefSnapshot) -> io::Result<()> {
+  let text = snapshot
+    .iter()
+    .map(|(name, oid)| format!("{} {}\n", oid, name))
+    .collect::<String>();
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let tmp = path.with_extension("tmp");
+  fs::write(&tmp, text)?;
+  fs::rename(tmp, path)
+}
@@ -1,11 +1,14 @@
 mod index;
 pub(crate) use index::index;
 
+mod activity;
+pub(crate) use activity::{activity, activity_feed};
+
 mod repo_refs_feed;
-pub(crate) use repo_refs_feed::repo_refs_feed;
+pub(crate) use repo_refs_feed::{repo_refs_atom, repo_refs_feed, tags};
 
 mod repo_log_feed;
-pub(crate) use repo_log_feed::repo_log_feed;
+pub(crate) use repo_log_feed::{feed_content, repo_log_atom, repo_log_feed};
 
 mod repo_refs;
 pub(crate) use repo_refs::repo_refs;
@@ -17,7 +20,10 @@ mod repo_file;
 pub(crate) use repo_file::{repo_file, repo_file_raw};
 
 mod repo_commit;
-pub(crate) use repo_commit::repo_commit;
+pub(crate) use repo_commit::{commit_diff, patch_text, repo_commit, repo_commit_raw};
+
+mod repo_archive;
+pub(crate) use repo_archive::repo_archive;
 
 mod repo_tag;
 pub(crate) use repo_tag::repo_tag;
@@ -27,3 +33,21 @@ pub(crate) use static_resource::static_resource;
 
 mod repo_log;
 pub(crate) use repo_log::repo_log;
+
+mod repo_lists;
+pub(crate) use repo_lists::{applied, mailing_list, repo_lists, repo_thread};
+
+mod repo_patches;
+pub(crate) use repo_patches::{repo_patches, repo_series};
+
+mod repo_webhooks;
+pub(crate) use repo_webhooks::repo_webhooks;
+
+mod metrics;
+pub(crate) use metrics::metrics;
+
+mod health;
+pub(crate) use health::{healthz, readyz};
+
+mod api;
+pub(crate) use api::{api_blob, api_commit, api_commits, api_refs, api_repos, api_tree};
@@ -0,0 +1,124 @@
+use crate::route_prelude::*;
+use std::collections::HashMap;
+
+/// A commit or tag in one of the repositories
+struct Activity {
+  repo_name: String,
+  kind: &'static str,
+  title: String,
+  // link to the page of this item, relative to the repository
+  link: String,
+  signature: Signature<'static>,
+  message: String,
+  // the commit, for its diffstat in the feed
+  commit: Option<git2::Oid>,
+  // HTML shown as the content of the feed entry, only set for the feed
+  content: String,
+}
+
+#[derive(Template)]
+#[template(path = "activity.html")]
+struct ActivityTemplate {
+  activities: Vec<Activity>,
+}
+
+#[derive(Template)]
+#[template(path = "activity.xml")]
+struct ActivityFeedTemplate<'a> {
+  activities: Vec<Activity>,
+  base_url: &'a str,
+}
+
+/// Collect the newest commits and tags of all exported repositories, newest
+/// first.
+fn activities() -> Vec<Activity> {
+  let config = config();
+  let mut activities = Vec::new();
+
+  for repo in crate::exported_repos() {
+    if repo.is_empty().unwrap_or(true) {
+      continue;
+    }
+    let repo_name = filters::repo_name(&repo).unwrap().to_string();
+
+    let head = match repo.head().and_then(|head| head.peel_to_commit()) {
+      Ok(head) => head,
+      Err(e) => {
+        tide::log::warn!("can't read HEAD of {}: {}", repo_name, e);
+        continue;
+      }
+    };
+    let commits = if repo.is_shallow() {
+      vec![head]
+    } else {
+      let mut revwalk = repo.revwalk().unwrap();
+      revwalk.push(head.id()).unwrap();
+      revwalk.set_sorting(git2::Sort::TIME).unwrap();
+      revwalk
+        .filter_map(|oid| repo.find_commit(oid.ok()?).ok())
+        .take(config.log_per_page)
+        .collect()
+    };
+    activities.extend(commits.into_iter().map(|commit| Activity {
+      repo_name: repo_name.clone(),
+      kind: "commit",
+      title: commit.summary().unwrap_or("").into(),
+      link: format!("commit/{}", commit.id()),
+      signature: commit.committer().to_owned(),
+      message: commit.message().unwrap_or("").into(),
+      commit: Some(commit.id()),
+      content: String::new(),
+    }));
+
+    activities.extend(
+      super::tags(&repo)
+        .into_iter()
+        .map(|(link, name, signature, message)| Activity {
+          repo_name: repo_name.clone(),
+          kind: "tag",
+          title: name,
+          link,
+          signature,
+          message,
+          commit: None,
+          content: String::new(),
+        }),
+    );
+  }
+
+  // sort so that the newest activity is at the top
+  activities.sort_unstable_by(|a, b| a.signature.when().cmp(&b.signature.when()).reverse());
+  activities.truncate(config.log_per_page);
+  activities
+}
+
+pub(crate) async fn activity(_req: Request<()>) -> tide::Result {
+  Ok(
+    ActivityTemplate {
+      activities: activities(),
+    }
+    .into(),
+  )
+}
+
+pub(crate) async fn activity_feed(req: Request<()>) -> tide::Result {
+  let mut activities = activities();
+  // only the activities that are shown get their content, like diffstats
+  let mut repos = HashMap::new();
+  for activity in &mut activities {
+    if !repos.contains_key(&activity.repo_name) {
+      let repo = repo_from_request(&activity.repo_name)?;
+      repos.insert(activity.repo_name.clone(), repo);
+    }
+    let repo = &repos[&activity.repo_name];
+    let commit = activity.commit.and_then(|oid| repo.find_commit(oid).ok());
+    activity.content = super::feed_content(repo, &activity.message, commit.as_ref());
+  }
+  let tmpl = ActivityFeedTemplate {
+    activities,
+    base_url: &crate::base_url(&req),
+  };
+  let mut response: tide::Response = tmpl.into();
+  response.set_content_type("application/rss+xml");
+  Ok(response)
+}
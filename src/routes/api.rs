@@ -0,0 +1,429 @@
+use crate::route_prelude::*;
+use git2::{Delta, ErrorCode, ObjectType, Oid, Patch};
+use serde::Serialize;
+
+/// Respond with `result` as JSON. Errors are JSON as well instead of an error
+/// page, so they are not returned as errors to the middleware.
+fn json<T: Serialize>(result: tide::Result<T>) -> tide::Result {
+  let (status, body) = match result {
+    Ok(value) => (200, serde_json::to_value(value)?),
+    Err(e) => (
+      e.status().into(),
+      serde_json::json!({ "error": e.to_string() }),
+    ),
+  };
+  let mut response = Response::new(status);
+  response.set_body(body);
+  Ok(response)
+}
+
+/// Git errors about things that do not exist are shown as 404.
+fn git_error(e: git2::Error) -> tide::Error {
+  let status = match e.code() {
+    ErrorCode::NotFound | ErrorCode::InvalidSpec | ErrorCode::UnbornBranch => 404,
+    ErrorCode::Ambiguous => 400,
+    _ => 500,
+  };
+  tide::Error::from_str(status, e.message().to_string())
+}
+
+fn time(time: git2::Time) -> String {
+  filters::format_datetime(time, "%+").unwrap()
+}
+
+#[derive(Serialize)]
+struct ApiSignature {
+  name: String,
+  email: String,
+  time: String,
+}
+
+impl From<Signature<'_>> for ApiSignature {
+  fn from(signature: Signature) -> Self {
+    Self {
+      name: String::from_utf8_lossy(signature.name_bytes()).into(),
+      email: String::from_utf8_lossy(signature.email_bytes()).into(),
+      time: time(signature.when()),
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct ApiRepo {
+  name: String,
+  description: String,
+  owner: String,
+  last_modified: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ApiCommit {
+  id: String,
+  summary: String,
+  message: String,
+  author: ApiSignature,
+  committer: ApiSignature,
+  parents: Vec<String>,
+}
+
+impl From<&Commit<'_>> for ApiCommit {
+  fn from(commit: &Commit) -> Self {
+    Self {
+      id: commit.id().to_string(),
+      summary: String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default()).into(),
+      message: String::from_utf8_lossy(commit.message_bytes()).into(),
+      author: commit.author().into(),
+      committer: commit.committer().into(),
+      parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct ApiBranch {
+  name: String,
+  commit: String,
+}
+
+#[derive(Serialize)]
+struct ApiTag {
+  name: String,
+  commit: String,
+  tagger: ApiSignature,
+  message: String,
+}
+
+#[derive(Serialize)]
+struct ApiRefs {
+  head: Option<String>,
+  branches: Vec<ApiBranch>,
+  tags: Vec<ApiTag>,
+}
+
+#[derive(Serialize)]
+struct ApiCommits {
+  commits: Vec<ApiCommit>,
+  /// the `ref` to request the next page with
+  next: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ApiFileStat {
+  path: String,
+  old_path: Option<String>,
+  status: &'static str,
+  insertions: usize,
+  deletions: usize,
+}
+
+#[derive(Serialize)]
+struct ApiDiffstat {
+  files_changed: usize,
+  insertions: usize,
+  deletions: usize,
+  files: Vec<ApiFileStat>,
+}
+
+#[derive(Serialize)]
+struct ApiCommitDetails {
+  #[serde(flatten)]
+  commit: ApiCommit,
+  diffstat: ApiDiffstat,
+}
+
+#[derive(Serialize)]
+struct ApiTreeEntry {
+  name: String,
+  kind: &'static str,
+  mode: i32,
+  id: String,
+  size: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ApiTree {
+  path: String,
+  commit: String,
+  entries: Vec<ApiTreeEntry>,
+}
+
+#[derive(Serialize)]
+struct ApiBlob {
+  path: String,
+  commit: String,
+  id: String,
+  size: usize,
+  /// whether the file is binary, not known for files that are too large
+  binary: Option<bool>,
+  /// the text of the file, unless it is binary or larger than `max_blob_size`
+  content: Option<String>,
+  raw_url: String,
+}
+
+fn kind(kind: Option<ObjectType>) -> &'static str {
+  match kind {
+    Some(ObjectType::Tree) => "tree",
+    Some(ObjectType::Blob) => "blob",
+    // submodule
+    Some(ObjectType::Commit) => "commit",
+    _ => "unknown",
+  }
+}
+
+fn resolve_commit<'a>(repo: &'a Repository, spec: &str) -> tide::Result<Commit<'a>> {
+  repo
+    .revparse_single(spec)
+    .and_then(|obj| obj.peel_to_commit())
+    .map_err(git_error)
+}
+
+pub(crate) async fn api_repos(_req: Request<()>) -> tide::Result {
+  json(Ok(
+    crate::exported_repos()
+      .iter()
+      .map(|repo| ApiRepo {
+        name: filters::repo_name(repo).unwrap().into(),
+        description: filters::description(repo).unwrap(),
+        owner: filters::repo_owner(repo).unwrap(),
+        last_modified: repo
+          .head()
+          .ok()
+          .map(|_| time(filters::last_modified(repo).unwrap())),
+      })
+      .collect::<Vec<_>>(),
+  ))
+}
+
+pub(crate) async fn api_refs(req: Request<()>) -> tide::Result {
+  json(refs(&req))
+}
+
+fn refs(req: &Request<()>) -> tide::Result<ApiRefs> {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+
+  let head = repo
+    .head()
+    .ok()
+    .and_then(|head| head.shorthand().map(String::from));
+  let branches = repo
+    .branches(Some(git2::BranchType::Local))?
+    .filter_map(Result::ok)
+    .filter_map(|(branch, _)| {
+      Some(ApiBranch {
+        name: branch.name().ok()??.into(),
+        commit: branch.get().peel_to_commit().ok()?.id().to_string(),
+      })
+    })
+    .collect();
+  let tags = super::tags(&repo)
+    .into_iter()
+    .filter_map(|(_, name, tagger, message)| {
+      Some(ApiTag {
+        commit: resolve_commit(&repo, &name).ok()?.id().to_string(),
+        name,
+        tagger: tagger.into(),
+        message,
+      })
+    })
+    .collect();
+
+  Ok(ApiRefs {
+    head,
+    branches,
+    tags,
+  })
+}
+
+pub(crate) async fn api_commits(req: Request<()>) -> tide::Result {
+  json(commits(&req))
+}
+
+fn commits(req: &Request<()>) -> tide::Result<ApiCommits> {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let config = config();
+  let query = req.url().query_pairs().collect::<Vec<_>>();
+  let param = |name| query.iter().find(|(key, _)| key == name).map(|(_, v)| v);
+  let spec = param("ref").map_or("HEAD", |spec| spec.as_ref());
+  let commit = resolve_commit(&repo, spec)?;
+
+  let mut revwalk = repo.revwalk()?;
+  revwalk.push(commit.id())?;
+  revwalk.set_sorting(git2::Sort::TIME)?;
+  let commits = revwalk.filter_map(|oid| repo.find_commit(oid.ok()?).ok());
+  let mut commits = if let Some(path) = param("path") {
+    let mut options = DiffOptions::new();
+    options.pathspec(path.as_ref());
+    commits
+      .take(config.max_revwalk_depth)
+      .filter(|commit| crate::commit_touches(&repo, commit, &mut options))
+      .take(config.log_per_page + 1)
+      .collect::<Vec<_>>()
+  } else {
+    commits.take(config.log_per_page + 1).collect()
+  };
+
+  // same as the pages of the log
+  let next = if commits.len() > config.log_per_page {
+    commits.pop();
+    let skipped = spec
+      .rsplit_once('~')
+      .and_then(|(base, n)| Some((base, n.parse::<usize>().ok()?)));
+    Some(match skipped {
+      Some((base, n)) => format!("{}~{}", base, n + config.log_per_page),
+      None => format!("{}~{}", spec, config.log_per_page),
+    })
+  } else {
+    None
+  };
+
+  Ok(ApiCommits {
+    commits: commits.iter().map(ApiCommit::from).collect(),
+    next,
+  })
+}
+
+pub(crate) async fn api_commit(req: Request<()>) -> tide::Result {
+  json(commit(&req))
+}
+
+fn commit(req: &Request<()>) -> tide::Result<ApiCommitDetails> {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let id = Oid::from_str(req.param("commit")?)
+    .map_err(|_| tide::Error::from_str(400, "not a commit id"))?;
+  let commit = repo.find_commit(id).map_err(git_error)?;
+  let diff = super::commit_diff(&repo, &commit)?;
+  let stats = diff.stats()?;
+
+  let files = (0..diff.deltas().len())
+    .filter_map(|i| {
+      let delta = diff.get_delta(i)?;
+      let (_, insertions, deletions) = Patch::from_diff(&diff, i)
+        .ok()
+        .flatten()
+        .and_then(|patch| patch.line_stats().ok())
+        .unwrap_or_default();
+      let path = |file: git2::DiffFile| file.path().map(|path| path.to_string_lossy().into_owned());
+      Some(ApiFileStat {
+        path: path(delta.new_file()).or_else(|| path(delta.old_file()))?,
+        old_path: match delta.status() {
+          Delta::Renamed | Delta::Copied => path(delta.old_file()),
+          _ => None,
+        },
+        status: match delta.status() {
+          Delta::Added => "added",
+          Delta::Deleted => "deleted",
+          Delta::Renamed => "renamed",
+          Delta::Copied => "copied",
+          Delta::Typechange => "typechange",
+          _ => "modified",
+        },
+        insertions,
+        deletions,
+      })
+    })
+    .collect();
+
+  Ok(ApiCommitDetails {
+    commit: ApiCommit::from(&commit),
+    diffstat: ApiDiffstat {
+      files_changed: stats.files_changed(),
+      insertions: stats.insertions(),
+      deletions: stats.deletions(),
+      files,
+    },
+  })
+}
+
+pub(crate) async fn api_tree(req: Request<()>) -> tide::Result {
+  json(tree(&req))
+}
+
+fn tree(req: &Request<()>) -> tide::Result<ApiTree> {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let commit = resolve_commit(&repo, req.param("ref")?)?;
+  let path = req.param("object_name").unwrap_or_default();
+
+  let tree = if path.is_empty() {
+    commit.tree()?
+  } else {
+    commit
+      .tree()?
+      .get_path(Path::new(path))
+      .map_err(git_error)?
+      .to_object(&repo)?
+      .into_tree()
+      .map_err(|_| tide::Error::from_str(400, "not a directory, use the blob endpoint"))?
+  };
+
+  // only the headers, loading every file would be slow
+  let odb = repo.odb()?;
+  let entries = tree
+    .iter()
+    .map(|entry| ApiTreeEntry {
+      name: String::from_utf8_lossy(entry.name_bytes()).into(),
+      kind: kind(entry.kind()),
+      mode: entry.filemode(),
+      id: entry.id().to_string(),
+      size: match entry.kind() {
+        Some(ObjectType::Blob) => odb.read_header(entry.id()).ok().map(|(size, _)| size),
+        _ => None,
+      },
+    })
+    .collect();
+
+  Ok(ApiTree {
+    path: path.into(),
+    commit: commit.id().to_string(),
+    entries,
+  })
+}
+
+pub(crate) async fn api_blob(req: Request<()>) -> tide::Result {
+  json(blob(&req))
+}
+
+fn blob(req: &Request<()>) -> tide::Result<ApiBlob> {
+  let base_url = crate::base_url(req);
+  let repo_name = req.param("repo_name")?;
+  let repo = repo_from_request(repo_name)?;
+  let commit = resolve_commit(&repo, req.param("ref")?)?;
+  let path = req.param("object_name")?;
+
+  let entry = commit
+    .tree()?
+    .get_path(Path::new(path))
+    .map_err(git_error)?;
+  if entry.kind() != Some(ObjectType::Blob) {
+    return Err(tide::Error::from_str(
+      400,
+      "not a file, use the tree endpoint",
+    ));
+  }
+  let (size, _) = repo.odb()?.read_header(entry.id())?;
+
+  // large files are only available from the raw URL
+  let (binary, content) = if size > config().max_blob_size {
+    (None, None)
+  } else {
+    let blob = repo.find_blob(entry.id())?;
+    let binary = blob.is_binary();
+    let content = (!binary).then(|| String::from_utf8_lossy(blob.content()).into());
+    (Some(binary), content)
+  };
+
+  Ok(ApiBlob {
+    path: path.into(),
+    commit: commit.id().to_string(),
+    id: entry.id().to_string(),
+    size,
+    binary,
+    content,
+    raw_url: format!(
+      "{}/{}/tree/{}/raw/{}",
+      base_url,
+      repo_name,
+      commit.id(),
+      path
+    ),
+  })
+}
@@ -0,0 +1,51 @@
+use crate::route_prelude::*;
+
+/// Respond with a small JSON body that is not replaced by an error page.
+fn json_response(status: u16, body: serde_json::Value) -> Response {
+  let mut response = Response::new(status);
+  response.set_body(body);
+  response.insert_header("Cache-Control", "no-store");
+  response
+}
+
+/// The server is running and able to respond.
+pub(crate) async fn healthz(_req: Request<()>) -> tide::Result {
+  Ok(json_response(200, serde_json::json!({ "status": "ok" })))
+}
+
+/// The server is able to show repositories.
+pub(crate) async fn readyz(_req: Request<()>) -> tide::Result {
+  let repos_root = match fs::read_dir(&config().repos_root) {
+    Ok(_) => Ok(()),
+    Err(e) => Err(format!("can't read repositories root: {}", e)),
+  };
+  let syntaxes = if SYNTAXES.syntaxes().is_empty() {
+    Err("no syntaxes loaded".to_string())
+  } else {
+    Ok(())
+  };
+  let repos = if crate::exported_repos().is_empty() {
+    Err("no exported repository can be opened".to_string())
+  } else {
+    Ok(())
+  };
+
+  let checks = [
+    ("repos_root", repos_root),
+    ("syntaxes", syntaxes),
+    ("repositories", repos),
+  ];
+  let ready = checks.iter().all(|(_, result)| result.is_ok());
+  let checks = checks
+    .into_iter()
+    .map(|(name, result)| (name.to_string(), result.err().unwrap_or("ok".into()).into()))
+    .collect::<serde_json::Map<_, _>>();
+
+  Ok(json_response(
+    if ready { 200 } else { 503 },
+    serde_json::json!({
+      "status": if ready { "ok" } else { "unavailable" },
+      "checks": checks,
+    }),
+  ))
+}
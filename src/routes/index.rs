@@ -18,36 +18,30 @@ pub(crate) async fn index(req: Request<()>) -> tide::Result {
       })
       .collect::<std::collections::HashMap<_, _>>();
     if let Some(repo) = query.get("p") {
+      let base_path = &config().base_path;
       return Ok(
         tide::Redirect::permanent(match query.get("a") {
-          None | Some(&"summary") => format!("/{}/", repo),
-          Some(&"commit") | Some(&"commitdiff") => {
-            format!("/{}/commit/{}", repo, query.get("h").cloned().unwrap_or(""))
-          }
-          Some(&"shortlog") | Some(&"log") => {
-            format!("/{}/log/{}", repo, query.get("h").cloned().unwrap_or(""))
-          }
-          Some(_) => "/".into(),
+          None | Some(&"summary") => format!("{}/{}/", base_path, repo),
+          Some(&"commit") | Some(&"commitdiff") => format!(
+            "{}/{}/commit/{}",
+            base_path,
+            repo,
+            query.get("h").cloned().unwrap_or("")
+          ),
+          Some(&"shortlog") | Some(&"log") => format!(
+            "{}/{}/log/{}",
+            base_path,
+            repo,
+            query.get("h").cloned().unwrap_or("")
+          ),
+          Some(_) => format!("{}/", base_path),
         })
         .into(),
       );
     }
   }
 
-  let repos = fs::read_dir(&CONFIG.repos_root)
-    .map(|entries| {
-      entries
-        .filter_map(|entry| Some(entry.ok()?.path()))
-        .filter_map(|entry| Repository::open(entry).ok())
-        .filter(|repo| {
-          // check for the export file in the git directory
-          // (the .git subfolder for non-bare repos)
-          repo.path().join(&CONFIG.export_ok).exists()
-        })
-        .collect::<Vec<_>>()
-    })
-    .map_err(|e| tide::log::warn!("can't read repositories: {}", e))
-    .unwrap_or_default();
+  let repos = crate::exported_repos();
   let index_template = IndexTemplate { repos };
 
   Ok(index_template.into())
@@ -0,0 +1,9 @@
+use crate::route_prelude::*;
+
+/// Serve the metrics in the Prometheus text format
+pub(crate) async fn metrics(_req: Request<()>) -> tide::Result {
+  let mut response = Response::new(200);
+  response.set_body(crate::metrics::render());
+  response.set_content_type("text/plain; version=0.0.4");
+  Ok(response)
+}
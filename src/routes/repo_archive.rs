@@ -0,0 +1,242 @@
+use crate::route_prelude::*;
+use flate2::{write::GzEncoder, Compression};
+use git2::{ObjectType, Oid, TreeWalkMode, TreeWalkResult};
+use std::{
+  fmt::Write,
+  fs::File,
+  io::{Seek, SeekFrom},
+  path::PathBuf,
+  time::{Duration, Instant},
+};
+
+/// Why an archive could not be created.
+enum ArchiveError {
+  /// the files are larger than `max_archive_size` in total
+  TooLarge,
+  /// creating the archive took longer than `request_timeout`
+  TimedOut,
+  Git(git2::Error),
+  Io(std::io::Error),
+}
+
+impl From<git2::Error> for ArchiveError {
+  fn from(e: git2::Error) -> Self {
+    Self::Git(e)
+  }
+}
+
+impl From<std::io::Error> for ArchiveError {
+  fn from(e: std::io::Error) -> Self {
+    Self::Io(e)
+  }
+}
+
+/// A file, directory or symbolic link in the archive.
+struct Entry {
+  path: String,
+  id: Oid,
+  mode: i32,
+  kind: Option<ObjectType>,
+}
+
+/// Create a gzipped tarball of the tree of `commit`, with every path starting
+/// with `prefix`.
+///
+/// The archive is written to a temporary file instead of memory, because it can
+/// be as large as `max_size`. The file is already deleted, so it goes away
+/// when it is closed.
+fn archive(
+  git_dir: &Path,
+  commit: Oid,
+  prefix: &str,
+  max_size: usize,
+  deadline: Instant,
+) -> Result<File, ArchiveError> {
+  let repo = Repository::open(git_dir)?;
+  let commit = repo.find_commit(commit)?;
+  let odb = repo.odb()?;
+
+  // only look at the sizes first, so large archives are refused quickly
+  let mut entries = Vec::new();
+  let mut size = 0;
+  let mut result = Ok(());
+  commit.tree()?.walk(TreeWalkMode::PreOrder, |root, entry| {
+    if Instant::now() > deadline {
+      result = Err(ArchiveError::TimedOut);
+      return TreeWalkResult::Abort;
+    }
+    let kind = entry.kind();
+    if kind == Some(ObjectType::Blob) {
+      match odb.read_header(entry.id()) {
+        Ok((blob_size, _)) => size += blob_size,
+        Err(e) => {
+          result = Err(e.into());
+          return TreeWalkResult::Abort;
+        }
+      }
+      if size > max_size {
+        result = Err(ArchiveError::TooLarge);
+        return TreeWalkResult::Abort;
+      }
+    }
+    entries.push(Entry {
+      path: format!("{}{}{}", prefix, root, entry.name().unwrap_or_default()),
+      id: entry.id(),
+      mode: entry.filemode(),
+      kind,
+    });
+    TreeWalkResult::Ok
+  })?;
+  result?;
+
+  let mtime = commit.time().seconds().max(0) as u64;
+  let file = tempfile::tempfile()?;
+  let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+  let mut header = tar::Header::new_gnu();
+  header.set_entry_type(tar::EntryType::Directory);
+  header.set_mode(0o755);
+  header.set_mtime(mtime);
+  header.set_size(0);
+  tar.append_data(&mut header, prefix, std::io::empty())?;
+  for entry in entries {
+    if Instant::now() > deadline {
+      return Err(ArchiveError::TimedOut);
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_mtime(mtime);
+    match entry.kind {
+      Some(ObjectType::Tree) => {
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        tar.append_data(&mut header, entry.path + "/", std::io::empty())?;
+      }
+      Some(ObjectType::Blob) => {
+        let blob = repo.find_blob(entry.id)?;
+        if entry.mode == i32::from(git2::FileMode::Link) {
+          header.set_entry_type(tar::EntryType::Symlink);
+          header.set_mode(0o777);
+          header.set_size(0);
+          let target = PathBuf::from(String::from_utf8_lossy(blob.content()).into_owned());
+          tar.append_link(&mut header, entry.path, target)?;
+        } else {
+          header.set_entry_type(tar::EntryType::Regular);
+          header.set_mode(if entry.mode == i32::from(git2::FileMode::BlobExecutable) {
+            0o755
+          } else {
+            0o644
+          });
+          header.set_size(blob.size() as u64);
+          tar.append_data(&mut header, entry.path, blob.content())?;
+        }
+      }
+      // submodules are not part of the repository
+      _ => {}
+    }
+  }
+  let mut file = tar.into_inner()?.finish()?;
+  file.seek(SeekFrom::Start(0))?;
+  Ok(file)
+}
+
+/// The Content-Disposition header for downloading a file called `name`.
+///
+/// `filename` only reliably supports ASCII without quotes and backslashes, so
+/// other characters are replaced there, and the whole name is given in
+/// `filename*` for clients that support it.
+fn content_disposition(name: &str) -> String {
+  let ascii = name
+    .chars()
+    .map(|c| match c {
+      ' '..='~' if c != '"' && c != '\\' => c,
+      _ => '_',
+    })
+    .collect::<String>();
+  let mut header = format!("attachment; filename=\"{}\"", ascii);
+  if ascii != name {
+    write!(
+      header,
+      "; filename*=UTF-8''{}",
+      percent_encoding::utf8_percent_encode(name, percent_encoding::NON_ALPHANUMERIC)
+    )
+    .unwrap();
+  }
+  header
+}
+
+/// Serve the files of a commit as a gzipped tarball, like `git archive`.
+pub(crate) async fn repo_archive(req: Request<()>) -> tide::Result {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let spec = req
+    .param("archive")?
+    .strip_suffix(".tar.gz")
+    .ok_or_else(|| tide::Error::from_str(404, "Archives are only available as .tar.gz."))?;
+  let commit = repo.revparse_single(spec)?.peel_to_commit()?.id();
+
+  // like "agit-v1.0/", so the files are not extracted into the current directory
+  let name = format!(
+    "{}-{}",
+    filters::repo_name(&repo).unwrap().trim_end_matches(".git"),
+    spec.replace('/', "-")
+  );
+  let prefix = format!("{}/", name);
+  let config = config();
+  let max_size = config.max_archive_size;
+  let timeout = config.request_timeout;
+  let git_dir = repo.path().to_path_buf();
+  let deadline = Instant::now() + Duration::from_secs(timeout);
+  let archive =
+    async_std::task::spawn_blocking(move || archive(&git_dir, commit, &prefix, max_size, deadline))
+      .await;
+
+  match archive {
+    Ok(archive) => {
+      let len = archive.metadata()?.len() as usize;
+      let archive = async_std::io::BufReader::new(async_std::fs::File::from(archive));
+      Ok(
+        Response::builder(200)
+          .body(tide::Body::from_reader(archive, Some(len)))
+          .content_type("application/gzip")
+          .header(
+            "Content-Disposition",
+            content_disposition(&format!("{}.tar.gz", name)),
+          )
+          .build(),
+      )
+    }
+    Err(ArchiveError::TooLarge) => Err(tide::Error::from_str(
+      413,
+      format!(
+        "This archive would be larger than {} bytes, please clone the repository instead.",
+        max_size
+      ),
+    )),
+    Err(ArchiveError::TimedOut) => Err(tide::Error::from_str(
+      503,
+      format!(
+        "Creating this archive took longer than {} seconds, please clone the repository instead.",
+        timeout
+      ),
+    )),
+    Err(ArchiveError::Git(e)) => Err(e.into()),
+    Err(ArchiveError::Io(e)) => Err(e.into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn content_disposition_escapes_the_name() {
+    assert_eq!(
+      content_disposition("agit-v1.0.tar.gz"),
+      "attachment; filename=\"agit-v1.0.tar.gz\""
+    );
+    assert_eq!(
+      content_disposition("agit-say-\"hi\"-ü.tar.gz"),
+      "attachment; filename=\"agit-say-_hi_-_.tar.gz\"; \
+       filename*=UTF-8''agit%2Dsay%2D%22hi%22%2D%C3%BC%2Etar%2Egz"
+    );
+  }
+}
@@ -1,4 +1,4 @@
-use crate::route_prelude::*;
+use crate::{mail, route_prelude::*, verify::Verification};
 
 #[derive(Template)]
 #[template(path = "commit.html")] // using the template in this path, relative
@@ -6,6 +6,10 @@ struct RepoCommitTemplate<'a> {
   repo: &'a Repository,
   commit: Commit<'a>,
   diff: &'a Diff<'a>,
+  diff_text: &'a str,
+  verification: Option<Verification>,
+  /// the message id and subject of the email the commit was sent as
+  discussion: Option<(String, String)>,
 }
 
 impl RepoCommitTemplate<'_> {
@@ -13,42 +17,6 @@ impl RepoCommitTemplate<'_> {
     self.commit.parent_ids().collect()
   }
 
-  fn diff(&self) -> String {
-    let mut buf = String::new();
-    self
-      .diff
-      .print(
-        git2::DiffFormat::Patch,
-        |_delta, _hunk, line| match str::from_utf8(line.content()) {
-          Ok(content) => {
-            match line.origin() {
-              'F' | 'H' => {}
-              c @ ' ' | c @ '+' | c @ '-' | c @ '=' | c @ '<' | c @ '>' => buf.push(c),
-              _ => unreachable!(),
-            }
-            buf.push_str(content);
-            true
-          }
-          Err(_) => {
-            buf.push_str("Cannot display diff for binary file.");
-            false
-          }
-        },
-      )
-      .unwrap();
-
-    // highlight the diff
-    let syntax = SYNTAXES
-      .find_syntax_by_name("Diff")
-      .expect("diff syntax missing");
-    let mut highlighter =
-      ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, ClassStyle::Spaced);
-    LinesWithEndings::from(&buf).for_each(|line| {
-      let _ = highlighter.parse_html_for_line_which_includes_newline(line);
-    });
-    highlighter.finalize()
-  }
-
   fn refs(&self) -> String {
     use git2::{BranchType, DescribeFormatOptions, DescribeOptions};
 
@@ -63,11 +31,12 @@ impl RepoCommitTemplate<'_> {
     if let Ok(descr) = descr {
       // this can be a tag or lightweight tag, the refs path will redirect
       html += &format!(
-        r#"<a href="/{0}/refs/{1}" class="badge tag">{1}</a>"#,
+        r#"<a href="{2}/{0}/refs/{1}" class="badge tag">{1}</a>"#,
         filters::repo_name(self.repo).unwrap(),
         descr
           .format(Some(DescribeFormatOptions::new().abbreviated_size(0)))
           .unwrap(),
+        config().base_path,
       );
     }
 
@@ -81,9 +50,10 @@ impl RepoCommitTemplate<'_> {
     for branch in branches {
       // branch is not a reference, just a fancy name for a commit
       html += &format!(
-        r#" <a href="/{0}/log/{1}" class="badge branch">{1}</a>"#,
+        r#" <a href="{2}/{0}/log/{1}" class="badge branch">{1}</a>"#,
         filters::repo_name(self.repo).unwrap(),
         branch.name().unwrap().unwrap(),
+        config().base_path,
       );
     }
 
@@ -91,12 +61,53 @@ impl RepoCommitTemplate<'_> {
   }
 }
 
-pub(crate) async fn repo_commit(req: Request<()>) -> tide::Result {
-  let repo = repo_from_request(req.param("repo_name")?)?;
-  let commit = repo
-    .revparse_single(req.param("commit")?)?
-    .peel_to_commit()?;
+/// Render a diff as a patch, giving up if it grows beyond `limit` bytes.
+pub(crate) fn patch_text(diff: &Diff, limit: usize) -> Option<String> {
+  let mut buf = String::new();
+  let mut too_large = false;
+  // returning false from the callback stops printing, which git2 reports as
+  // an error, so the result is deliberately ignored here
+  let _ = diff.print(
+    git2::DiffFormat::Patch,
+    |_delta, _hunk, line| match str::from_utf8(line.content()) {
+      Ok(content) => {
+        match line.origin() {
+          'F' | 'H' | 'B' => {}
+          c @ ' ' | c @ '+' | c @ '-' | c @ '=' | c @ '<' | c @ '>' => buf.push(c),
+          _ => unreachable!(),
+        }
+        buf.push_str(content);
+        too_large = buf.len() > limit;
+        !too_large
+      }
+      Err(_) => {
+        buf.push_str("Cannot display diff for binary file.");
+        false
+      }
+    },
+  );
+  (!too_large).then_some(buf)
+}
 
+/// Find the email on the mailing list that contained the same changes as
+/// `commit`. If the patch was sent several times, the newest one is used.
+fn discussion(repo: &Repository, commit: &Commit) -> Option<(String, String)> {
+  let threads = mail::threads(&mail::mailbox(repo)?).ok()?;
+  let patch_id = mail::commit_patch_id(repo, commit)?;
+  threads
+    .iter()
+    .flat_map(|thread| &thread.messages)
+    .map(|message| &message.email)
+    .filter(|email| email.patch_id == Some(patch_id))
+    .max_by_key(|email| email.date.seconds())
+    .map(|email| (email.id.clone(), email.subject.clone()))
+}
+
+/// Diff a commit against its first parent, detecting renamed files.
+pub(crate) fn commit_diff<'a>(
+  repo: &'a Repository,
+  commit: &Commit,
+) -> Result<Diff<'a>, git2::Error> {
   // This is identical to getting "commit^" and on merges this will be the
   // merged into branch before the merge.
   let parent_tree = commit.parent(0).ok().map(|parent| parent.tree().unwrap());
@@ -105,12 +116,88 @@ pub(crate) async fn repo_commit(req: Request<()>) -> tide::Result {
   let mut find_options = git2::DiffFindOptions::new();
   // try to find moved/renamed files
   find_options.all(true);
-  diff.find_similar(Some(&mut find_options)).unwrap();
+  diff.find_similar(Some(&mut find_options))?;
+  Ok(diff)
+}
 
-  let tmpl = RepoCommitTemplate {
-    repo: &repo,
-    commit,
-    diff: &diff,
+pub(crate) async fn repo_commit(req: Request<()>) -> tide::Result {
+  // the repository can't be kept while the signature is verified
+  let (git_dir, oid) = {
+    let repo = repo_from_request(req.param("repo_name")?)?;
+    let commit = repo
+      .revparse_single(req.param("commit")?)?
+      .peel_to_commit()?;
+    (repo.path().to_path_buf(), commit.id())
   };
-  Ok(tmpl.into())
+  let verification = crate::verify::commit(git_dir, oid).await;
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let commit = repo.find_commit(oid)?;
+
+  let diff = commit_diff(&repo, &commit)?;
+  let discussion = discussion(&repo, &commit);
+
+  match patch_text(&diff, config().max_diff_size) {
+    Some(patch) => {
+      // the diff is highlighted while it is being sent, so the template
+      // only gets a placeholder
+      let page = RepoCommitTemplate {
+        repo: &repo,
+        commit,
+        diff: &diff,
+        diff_text: highlight::PLACEHOLDER,
+        verification,
+        discussion,
+      }
+      .render()?;
+      let syntax = SYNTAXES
+        .find_syntax_by_name("Diff")
+        .expect("diff syntax missing");
+      Ok(highlight::stream_page(page, move || {
+        HighlightedLines::new(patch, syntax)
+      }))
+    }
+    None => {
+      let too_large = format!(
+        "This diff is too large to be displayed. <a href=\"{}/{}/commit/{}/raw\">View raw</a>",
+        config().base_path,
+        filters::repo_name(&repo).unwrap(),
+        commit.id()
+      );
+      let tmpl = RepoCommitTemplate {
+        repo: &repo,
+        commit,
+        diff: &diff,
+        diff_text: &too_large,
+        verification,
+        discussion,
+      };
+      Ok(tmpl.into())
+    }
+  }
+}
+
+/// Serve the diff of a commit as plain text, without any size limit.
+pub(crate) async fn repo_commit_raw(req: Request<()>) -> tide::Result {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let commit = repo
+    .revparse_single(req.param("commit")?)?
+    .peel_to_commit()?;
+
+  let diff = commit_diff(&repo, &commit)?;
+  // the bytes as they are, since files don't have to be UTF-8
+  let mut patch = Vec::new();
+  diff.print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+    if let c @ (' ' | '+' | '-' | '=' | '<' | '>') = line.origin() {
+      patch.push(c as u8);
+    }
+    patch.extend_from_slice(line.content());
+    true
+  })?;
+
+  Ok(
+    Response::builder(200)
+      .body(patch)
+      .content_type(http::mime::PLAIN)
+      .build(),
+  )
 }
@@ -7,7 +7,7 @@ struct RepoFileTemplate<'a> {
   path: &'a Path,
   file_text: &'a str,
   spec: &'a str,
-  last_commit: Commit<'a>,
+  last_commit: Option<Commit<'a>>,
 }
 
 pub(crate) async fn repo_file(req: Request<()>) -> tide::Result {
@@ -36,7 +36,6 @@ pub(crate) async fn repo_file(req: Request<()>) -> tide::Result {
 
   // TODO make sure I am escaping html properly here
   // TODO allow disabling of syntax highlighting
-  // TODO -- dont pull in memory, use iterators if possible
   let tmpl = match tree_obj.into_tree() {
     // this is a subtree
     Ok(tree) => crate::RepoTreeTemplate {
@@ -71,45 +70,65 @@ pub(crate) async fn repo_file(req: Request<()>) -> tide::Result {
         match mime.basetype() {
                     "text" => unreachable!("git detected this file as binary"),
                     "image" => format!(
-                        "<img src=\"/{}/tree/{spec}/raw/{}\" />",
+                        "<img src=\"{}/{}/tree/{spec}/raw/{}\" />",
+                        config().base_path,
                         req.param("repo_name").unwrap(),
                         path.display()
                     ),
                     tag@"audio"|tag@"video" => format!(
-                        "<{} src=\"/{}/tree/{spec}/raw/{}\" controls>Your browser does not have support for playing this {0} file.</{0}>",
+                        "<{0} src=\"{1}/{2}/tree/{spec}/raw/{3}\" controls>Your browser does not have support for playing this {0} file.</{0}>",
                         tag,
+                        config().base_path,
                         req.param("repo_name").unwrap(),
                         path.display()
                     ),
                     _ => "Cannot display binary file.".into()
                 }
+      } else if blob.size() > config().max_highlight_size {
+        format!(
+          "<p>This file is too large to be displayed ({}). <a href=\"{}/{}/tree/{spec}/raw/{}\">View raw</a></p>",
+          askama::filters::filesizeformat(&blob.size())?,
+          config().base_path,
+          req.param("repo_name").unwrap(),
+          path.display()
+        )
       } else {
         // get file contents from git object
-        let file_string = std::str::from_utf8(tree_obj.as_blob().unwrap().content())?;
-        // create a highlighter that uses CSS classes so we can use prefers-color-scheme
-        let mut highlighter =
-          ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, ClassStyle::Spaced);
-        LinesWithEndings::from(file_string).for_each(|line| {
-          let _ = highlighter.parse_html_for_line_which_includes_newline(line);
-        });
+        let file_string = String::from_utf8(blob.content().to_vec())?;
 
         // use oid so it is a permalink
         let prefix = format!(
-          "/{}/tree/{}/item/{}",
+          "{}/{}/tree/{}/item/{}",
+          config().base_path,
           req.param("repo_name").unwrap(),
           commit.id(),
           path.display()
         );
 
-        let mut output = String::from("<pre class=\"source\">\n");
-        for (n, line) in highlighter.finalize().lines().enumerate() {
-          output.push_str(&format!(
-            "<a href='{prefix}#L{0}' id='L{0}' class='line'>{0}</a>{line}\n",
-            n + 1,
-          ));
+        // the file is highlighted while it is being sent, so the template
+        // only gets a placeholder
+        let page = RepoFileTemplate {
+          repo: &repo,
+          path,
+          file_text: highlight::PLACEHOLDER,
+          spec,
+          last_commit,
         }
-        output.push_str("</pre>\n");
-        output
+        .render()?;
+        return Ok(highlight::stream_page(page, move || {
+          let lines =
+            HighlightedLines::new(file_string, syntax)
+              .enumerate()
+              .map(move |(n, line)| {
+                format!(
+                  "<a href='{prefix}#L{0}' id='L{0}' class='line'>{0}</a>{line}",
+                  n + 1,
+                )
+              });
+          std::iter::once("<pre class=\"source\">\n".to_string())
+            .chain(lines)
+            .chain(std::iter::once("</pre>\n".to_string()))
+        }));
       };
       RepoFileTemplate {
         repo: &repo,
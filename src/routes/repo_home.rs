@@ -1,4 +1,4 @@
-use crate::route_prelude::*;
+use crate::{mirror::PushMirror, route_prelude::*};
 
 lazy_static! {
   static ref CODE_REGEX: Regex =
@@ -6,11 +6,12 @@ lazy_static! {
 }
 
 #[derive(Template)]
-#[template(path = "repo.html")] 
+#[template(path = "repo.html")]
 struct RepoHomeTemplate<'a> {
   repo: &'a Repository,
   commits: Vec<Commit<'a>>,
   readme_text: String,
+  push_mirrors: Vec<PushMirror>,
 }
 
 pub(crate) async fn repo_home(req: Request<()>) -> tide::Result {
@@ -83,7 +84,10 @@ pub(crate) async fn repo_home(req: Request<()>) -> tide::Result {
   }
 
   // get the first few commits for a preview
-  let commits = if repo.is_shallow() {
+  let commits = if repo.is_empty()? {
+    // nothing has been pushed yet
+    Vec::new()
+  } else if repo.is_shallow() {
     tide::log::warn!("repository {:?} is only a shallow clone", repo.path());
     vec![repo.head()?.peel_to_commit().unwrap()]
   } else {
@@ -103,6 +107,7 @@ pub(crate) async fn repo_home(req: Request<()>) -> tide::Result {
       repo: &repo,
       commits,
       readme_text: highlighted,
+      push_mirrors: crate::mirror::push_mirrors(&repo),
     }
     .into(),
   )
@@ -0,0 +1,80 @@
+use crate::{
+  mail::{self, Email, Thread, Threads},
+  route_prelude::*,
+};
+use std::collections::HashMap;
+
+#[derive(Template)]
+#[template(path = "list-threads.html")]
+struct RepoListsTemplate<'a> {
+  repo: &'a Repository,
+  threads: &'a [Thread],
+}
+
+#[derive(Template)]
+#[template(path = "thread.html")]
+struct RepoThreadTemplate<'a> {
+  repo: &'a Repository,
+  thread: &'a Thread,
+  /// the commits that patches became, by message id
+  applied: HashMap<&'a str, Commit<'a>>,
+}
+
+/// The threads of the mailing list of `repo`.
+pub(crate) fn mailing_list(repo: &Repository) -> tide::Result<Threads> {
+  let path = mail::mailbox(repo)
+    .ok_or_else(|| tide::Error::from_str(404, "this repository has no mailing list."))?;
+  mail::threads(&path).map_err(|e| {
+    tide::log::warn!("can't read mailbox {:?}: {}", path, e);
+    tide::Error::from_str(500, "the mailing list could not be read.")
+  })
+}
+
+/// Find the commits that the patches in `emails` became, by their patch id.
+pub(crate) fn applied<'a>(
+  repo: &'a Repository,
+  emails: impl Iterator<Item = &'a Email>,
+) -> HashMap<&'a str, Commit<'a>> {
+  let patches = emails
+    .filter_map(|email| Some((email.patch_id?, email.id.as_str())))
+    .collect::<Vec<_>>();
+  if patches.is_empty() {
+    return HashMap::new();
+  }
+
+  let commits = mail::applied_commits(repo, patches.iter().map(|(patch_id, _)| *patch_id));
+  patches
+    .into_iter()
+    .filter_map(|(patch_id, id)| Some((id, repo.find_commit(*commits.get(&patch_id)?).ok()?)))
+    .collect()
+}
+
+pub(crate) async fn repo_lists(req: Request<()>) -> tide::Result {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let threads = mailing_list(&repo)?;
+
+  let tmpl = RepoListsTemplate {
+    repo: &repo,
+    threads: &threads,
+  };
+  Ok(tmpl.into())
+}
+
+pub(crate) async fn repo_thread(req: Request<()>) -> tide::Result {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let threads = mailing_list(&repo)?;
+  let id = percent_encoding::percent_decode_str(req.param("message_id")?).decode_utf8_lossy();
+
+  // any message of the thread can be used to find it
+  let thread = threads
+    .iter()
+    .find(|thread| thread.contains(&id))
+    .ok_or_else(|| tide::Error::from_str(404, "this message does not exist."))?;
+
+  let tmpl = RepoThreadTemplate {
+    repo: &repo,
+    thread,
+    applied: applied(&repo, thread.messages.iter().map(|message| &message.email)),
+  };
+  Ok(tmpl.into())
+}
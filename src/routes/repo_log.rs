@@ -1,4 +1,17 @@
 use crate::route_prelude::*;
+use git2::Oid;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use std::{
+  fmt::Write,
+  time::{Duration, Instant},
+};
+
+/// Characters that have to be escaped in a query parameter value
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+  .remove(b'/')
+  .remove(b'.')
+  .remove(b'-')
+  .remove(b'_');
 
 #[derive(Template)]
 #[template(path = "log.html")] // using the template in this path, relative
@@ -6,8 +19,126 @@ struct RepoLogTemplate<'a> {
   repo: &'a Repository,
   commits: Vec<Commit<'a>>,
   branch: &'a str,
+  // the file the history is shown for, if any
+  path: Option<&'a str>,
   // the spec the user should be linked to to see the next page of commits
   next_page: Option<String>,
+  // whether the search for commits touching a path gave up early
+  truncated: bool,
+  // the commit graph, one row per commit, or empty if it is not shown
+  graph: Vec<crate::graph::Row>,
+  // the position of the first commit of the page in the graph
+  offset: usize,
+}
+
+impl RepoLogTemplate<'_> {
+  /// Link to the feed for the commits shown on this page.
+  fn feed_url(&self, extension: &str) -> String {
+    let mut url = format!(
+      "{}/{}/log/{}/feed.{extension}",
+      config().base_path,
+      filters::repo_name(self.repo).unwrap(),
+      askama::filters::urlencode_strict(self.branch).unwrap()
+    );
+    if let Some(path) = self.path {
+      url.push_str("?path=");
+      url.extend(percent_encoding::utf8_percent_encode(path, QUERY_VALUE));
+    }
+    url
+  }
+
+  /// Link to the log at `spec`, with the graph starting at its `offset`th
+  /// commit if `graph` is set.
+  fn log_url(&self, spec: &str, graph: Option<usize>) -> String {
+    let mut url = format!(
+      "{}/{}/log/{}",
+      config().base_path,
+      filters::repo_name(self.repo).unwrap(),
+      askama::filters::urlencode_strict(spec).unwrap()
+    );
+    if let Some(path) = self.path {
+      url.push('/');
+      url.push_str(path);
+    }
+    match graph {
+      Some(0) => url.push_str("?graph"),
+      Some(offset) => write!(url, "?graph&offset={}", offset).unwrap(),
+      None => {}
+    }
+    url
+  }
+
+  /// Link to the same commits with the graph shown or hidden.
+  fn toggle_graph_url(&self) -> String {
+    if self.graph.is_empty() {
+      self.log_url(self.branch, Some(0))
+    } else if self.offset == 0 {
+      self.log_url(self.branch, None)
+    } else {
+      // the log without the graph is not paginated by position
+      self.log_url(&self.commits[0].id().to_string(), None)
+    }
+  }
+
+  fn next_page_url(&self) -> Option<String> {
+    let next_page = self.next_page.as_ref()?;
+    Some(if self.graph.is_empty() {
+      self.log_url(next_page, None)
+    } else {
+      self.log_url(
+        self.branch,
+        Some(self.offset.saturating_add(self.commits.len())),
+      )
+    })
+  }
+
+  /// The commits with their row of the graph, if it is shown.
+  fn rows(&self) -> impl Iterator<Item = (&Commit<'_>, Option<&crate::graph::Row>)> {
+    let graph = self.graph.iter().map(Some).chain(std::iter::repeat(None));
+    self.commits.iter().zip(graph)
+  }
+}
+
+/// Walk at most `max_depth` commits from `start`, until `deadline`, and lay out
+/// the graph of the `len` commits from the `offset`th one on.
+///
+/// Returns the ids of those commits, their rows of the graph and whether the
+/// walk stopped before the end of the page.
+fn graph_page(
+  git_dir: &Path,
+  start: Oid,
+  offset: usize,
+  len: usize,
+  max_depth: usize,
+  deadline: Instant,
+) -> Result<(Vec<Oid>, Vec<crate::graph::Row>, bool), git2::Error> {
+  let repo = Repository::open(git_dir)?;
+  let mut revwalk = repo.revwalk()?;
+  revwalk.push(start)?;
+  revwalk.set_sorting(git2::Sort::TIME)?;
+  // the lanes are laid out from the start, so they continue from the previous
+  // page
+  let mut timed_out = false;
+  let commits = revwalk
+    .filter_map(|oid| repo.find_commit(oid.ok()?).ok())
+    .take(max_depth)
+    .take_while(|_| {
+      timed_out = Instant::now() >= deadline;
+      !timed_out
+    })
+    .collect::<Vec<_>>();
+  let walk_truncated = commits.len() == max_depth || timed_out;
+  // parents have to come after all their children for the lanes to work
+  let commits = crate::graph::sort(commits);
+
+  let end = commits.len().min(offset.saturating_add(len));
+  let start = offset.min(end);
+  // the rows of a commit only depend on the commits before it
+  let mut rows = crate::graph::rows(&commits[..end]);
+  rows.drain(..start);
+  let ids = commits[start..end].iter().map(Commit::id).collect();
+  // only the last page says that the walk stopped early
+  Ok((ids, rows, walk_truncated && end - start < len))
 }
 
 pub(crate) async fn repo_log(req: Request<()>) -> tide::Result {
@@ -19,69 +150,113 @@ pub(crate) async fn repo_log(req: Request<()>) -> tide::Result {
     return Ok(tide::Redirect::temporary(url).into());
   }
 
+  // the graph doesn't make sense for the commits that touch a path, since they
+  // are not connected
+  let show_graph =
+    req.param("object_name").is_err() && req.url().query_pairs().any(|(key, _)| key == "graph");
+
+  // the position of the page in the graph, which is paginated by position
+  // because following first parents would skip commits of other lanes
+  let offset = req
+    .url()
+    .query_pairs()
+    .find(|(key, _)| key == "offset")
+    .and_then(|(_, value)| value.parse::<usize>().ok())
+    .unwrap_or(0);
+
+  let config = config();
+  let deadline = Instant::now() + Duration::from_secs(config.request_timeout);
+  let spec = req.param("ref").ok().map(|spec| {
+    percent_encoding::percent_decode_str(spec)
+      .decode_utf8_lossy()
+      .into_owned()
+  });
   let next_page_spec;
+  let mut truncated = false;
+  let mut graph = vec![];
   let mut commits = if repo.is_shallow() {
     tide::log::warn!("repository {:?} is only a shallow clone", repo.path());
     next_page_spec = "".into();
     vec![repo.head()?.peel_to_commit().unwrap()]
   } else {
-    let mut revwalk = repo.revwalk()?;
-    let r = req.param("ref").unwrap_or("HEAD");
-    revwalk.push(repo.revparse_single(r)?.peel_to_commit()?.id())?;
+    let r = spec.as_deref().unwrap_or("HEAD");
+    let start = repo.revparse_single(r)?.peel_to_commit()?.id();
 
     if let Some(i) = r.rfind('~') {
       // there is a tilde, try to find a number too
       let n = r[i + 1..].parse::<usize>().ok().unwrap_or(1);
-      next_page_spec = format!("{}~{}", &r[..i], n + CONFIG.log_per_page);
+      next_page_spec = format!("{}~{}", &r[..i], n + config.log_per_page);
     } else {
       // there was no tilde
-      next_page_spec = format!("{}~{}", r, CONFIG.log_per_page);
+      next_page_spec = format!("{}~{}", r, config.log_per_page);
     }
 
-    revwalk.set_sorting(git2::Sort::TIME).unwrap();
-    let commits = revwalk.filter_map(|oid| repo.find_commit(oid.unwrap()).ok()); // TODO error handling
-
-    // filter for specific file if necessary
-    if let Ok(path) = req.param("object_name") {
-      let mut options = DiffOptions::new();
-      options.pathspec(path);
-      commits
-        .filter(|commit|
-                // check that the given file was affected from any of the parents
-                commit.parents().any(|parent|
-                    repo.diff_tree_to_tree(
-                        Some(&commit.tree().unwrap()),
-                        Some(&parent.tree().unwrap()),
-                        Some(&mut options),
-                    ).unwrap().stats().unwrap().files_changed()>0
-                ))
-        .take(CONFIG.log_per_page + 1)
-        .collect()
+    if show_graph {
+      // walking and laying out up to `max_revwalk_depth` commits takes a while
+      let git_dir = repo.path().to_path_buf();
+      let (len, max_depth) = (config.log_per_page + 1, config.max_revwalk_depth);
+      let (ids, rows, walk_truncated) = async_std::task::spawn_blocking(move || {
+        graph_page(&git_dir, start, offset, len, max_depth, deadline)
+      })
+      .await?;
+      graph = rows;
+      truncated = walk_truncated;
+      ids
+        .into_iter()
+        .map(|id| repo.find_commit(id))
+        .collect::<Result<_, _>>()?
     } else {
-      commits.take(CONFIG.log_per_page + 1).collect()
+      let mut revwalk = repo.revwalk()?;
+      revwalk.push(start)?;
+      revwalk.set_sorting(git2::Sort::TIME).unwrap();
+      let commits = revwalk.filter_map(|oid| repo.find_commit(oid.unwrap()).ok()); // TODO error handling
+
+      if let Ok(path) = req.param("object_name") {
+        // filter for specific file if necessary
+        let mut options = DiffOptions::new();
+        options.pathspec(path);
+        let mut walked = 0;
+        let mut timed_out = false;
+        let commits = commits
+          .take(config.max_revwalk_depth)
+          .take_while(|_| {
+            timed_out = Instant::now() >= deadline;
+            !timed_out
+          })
+          .inspect(|_| walked += 1)
+          .filter(|commit| crate::commit_touches(&repo, commit, &mut options))
+          .take(config.log_per_page + 1)
+          .collect::<Vec<_>>();
+        truncated =
+          (walked == config.max_revwalk_depth || timed_out) && commits.len() <= config.log_per_page;
+        commits
+      } else {
+        commits.take(config.log_per_page + 1).collect()
+      }
     }
   };
 
   // check if there even is a next page
-  let next_page = if commits.len() < CONFIG.log_per_page + 1 {
+  let next_page = if commits.len() < config.log_per_page + 1 {
     None
   } else {
     // remove additional commit from next page check
     commits.pop();
+    graph.truncate(commits.len());
     Some(next_page_spec)
   };
 
   let head_branch = repo.head()?;
-  let branch = req
-    .param("ref")
-    .ok()
-    .or_else(|| head_branch.shorthand())
-    .unwrap();
+  let branch = spec.as_deref().or_else(|| head_branch.shorthand()).unwrap();
   let tmpl = RepoLogTemplate {
     repo: &repo,
     commits,
     branch,
+    path: req.param("object_name").ok(),
     next_page,
+    truncated,
+    graph,
+    offset,
   };
   Ok(tmpl.into())
 }
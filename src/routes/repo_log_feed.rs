@@ -1,15 +1,96 @@
 use crate::route_prelude::*;
+use crate::FeedContent;
 
 #[derive(Template)]
 #[template(path = "log.xml")]
 struct RepoLogFeedTemplate<'a> {
   repo: &'a Repository,
-  commits: Vec<Commit<'a>>,
+  entries: Vec<FeedEntry<'a>>,
   branch: &'a str,
+  // the file the history is shown for, if any
+  path: Option<&'a str>,
+  // URL of the repository
   base_url: &'a str,
+  // URL of the HTML page showing the same commits
+  page_url: &'a str,
+  // URL of this feed
+  self_url: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "log.atom", escape = "xml")]
+struct RepoLogAtomTemplate<'a> {
+  repo: &'a Repository,
+  entries: Vec<FeedEntry<'a>>,
+  branch: &'a str,
+  // the file the history is shown for, if any
+  path: Option<&'a str>,
+  // URL of the repository
+  base_url: &'a str,
+  // URL of the HTML page showing the same commits
+  page_url: &'a str,
+  // URL of this feed
+  self_url: &'a str,
+}
+
+struct FeedEntry<'a> {
+  commit: Commit<'a>,
+  // HTML shown as the content of the entry, may be empty
+  content: String,
+}
+
+impl<'a> FeedEntry<'a> {
+  fn new(repo: &'a Repository, commit: Commit<'a>) -> Self {
+    let content = feed_content(repo, commit.message().unwrap_or(""), Some(&commit));
+    FeedEntry { commit, content }
+  }
+}
+
+/// The HTML shown as the content of a feed entry with `message`, and the
+/// diffstat of `commit` if there is one, as configured with `feed_content`.
+/// Empty if nothing should be shown.
+pub(crate) fn feed_content(repo: &Repository, message: &str, commit: Option<&Commit>) -> String {
+  let config = config();
+  let mut text = String::new();
+  if config.feed_content.contains(&FeedContent::Message) {
+    text.push_str(message);
+  }
+  if let Some(commit) = commit.filter(|_| config.feed_content.contains(&FeedContent::Diffstat)) {
+    let stats = crate::routes::commit_diff(repo, commit)
+      .and_then(|diff| diff.stats())
+      .and_then(|stats| stats.to_buf(git2::DiffStatsFormat::FULL, 80));
+    if let Ok(stats) = stats {
+      if !text.is_empty() {
+        text.push('\n');
+      }
+      text.push_str(stats.as_str().unwrap_or_default());
+    }
+  }
+
+  if text.is_empty() {
+    text
+  } else {
+    format!(
+      "<pre>{}</pre>",
+      askama::MarkupDisplay::new_unsafe(text, askama::Html)
+    )
+  }
+}
+
+enum FeedFormat {
+  Rss,
+  Atom,
 }
 
 pub(crate) async fn repo_log_feed(req: Request<()>) -> tide::Result {
+  log_feed(req, FeedFormat::Rss)
+}
+
+pub(crate) async fn repo_log_atom(req: Request<()>) -> tide::Result {
+  log_feed(req, FeedFormat::Atom)
+}
+
+fn log_feed(req: Request<()>, format: FeedFormat) -> tide::Result {
   let repo = repo_from_request(req.param("repo_name")?)?;
   if repo.is_empty().unwrap() {
     // show a server error
@@ -19,45 +100,97 @@ pub(crate) async fn repo_log_feed(req: Request<()>) -> tide::Result {
     ));
   }
 
+  let path = req
+    .url()
+    .query_pairs()
+    .find(|(key, _)| key == "path")
+    .map(|(_, path)| path.into_owned());
+
+  // like in the links of the log, which encode the slashes of branch names
+  let spec = req.param("ref").ok().map(|spec| {
+    percent_encoding::percent_decode_str(spec)
+      .decode_utf8_lossy()
+      .into_owned()
+  });
+
   let commits = if repo.is_shallow() {
     tide::log::warn!("repository {:?} is only a shallow clone", repo.path());
     vec![repo.head()?.peel_to_commit().unwrap()]
   } else {
+    let config = config();
     let mut revwalk = repo.revwalk()?;
-    let r = req.param("ref").unwrap_or("HEAD");
+    let r = spec.as_deref().unwrap_or("HEAD");
     revwalk.push(repo.revparse_single(r)?.peel_to_commit()?.id())?;
 
     revwalk.set_sorting(git2::Sort::TIME).unwrap();
-    revwalk
-      .filter_map(|oid| repo.find_commit(oid.unwrap()).ok()) // TODO error handling
-      .take(CONFIG.log_per_page)
-      .collect()
+    let commits = revwalk.filter_map(|oid| repo.find_commit(oid.unwrap()).ok()); // TODO error handling
+
+    // filter for specific file if necessary
+    if let Some(path) = &path {
+      let mut options = DiffOptions::new();
+      options.pathspec(path);
+      commits
+        .take(config.max_revwalk_depth)
+        .filter(|commit| crate::commit_touches(&repo, commit, &mut options))
+        .take(config.log_per_page)
+        .collect()
+    } else {
+      commits.take(config.log_per_page).collect()
+    }
   };
+  let entries = commits
+    .into_iter()
+    .map(|commit| FeedEntry::new(&repo, commit))
+    .collect();
 
   let head_branch = repo.head()?;
-  let branch = req
-    .param("ref")
-    .ok()
-    .or_else(|| head_branch.shorthand())
-    .unwrap();
-
-  let mut url = req.url().clone();
-  {
-    let mut segments = url.path_segments_mut().unwrap();
-    segments.pop(); // pop "log.xml" or "feed.xml"
-    if req.param("ref").is_ok() {
-      segments.pop(); // pop ref
-      segments.pop(); // pop "log/"
-    }
-  }
+  let branch = spec.as_deref().or_else(|| head_branch.shorthand()).unwrap();
 
-  let tmpl = RepoLogFeedTemplate {
-    repo: &repo,
-    commits,
-    branch,
-    base_url: url.as_str(),
+  let base_url = format!("{}/{}", crate::base_url(&req), req.param("repo_name")?);
+  let log_url = format!(
+    "{base_url}/log/{}",
+    askama::filters::urlencode_strict(branch).unwrap()
+  );
+  let page_url = match &path {
+    Some(path) => format!("{log_url}/{path}"),
+    None => log_url,
+  };
+  // the request path includes the base path, which is part of the base URL
+  let request_path = req
+    .url()
+    .path()
+    .strip_prefix(&config().base_path)
+    .unwrap_or_default();
+  let self_url = match req.url().query() {
+    Some(query) => format!("{}{request_path}?{query}", crate::base_url(&req)),
+    None => format!("{}{request_path}", crate::base_url(&req)),
+  };
+
+  let mut response: tide::Response = match format {
+    FeedFormat::Rss => RepoLogFeedTemplate {
+      repo: &repo,
+      entries,
+      branch,
+      path: path.as_deref(),
+      base_url: &base_url,
+      page_url: &page_url,
+      self_url: &self_url,
+    }
+    .into(),
+    FeedFormat::Atom => RepoLogAtomTemplate {
+      repo: &repo,
+      entries,
+      branch,
+      path: path.as_deref(),
+      base_url: &base_url,
+      page_url: &page_url,
+      self_url: &self_url,
+    }
+    .into(),
   };
-  let mut response: tide::Response = tmpl.into();
-  response.set_content_type("application/rss+xml");
+  response.set_content_type(match format {
+    FeedFormat::Rss => "application/rss+xml",
+    FeedFormat::Atom => "application/atom+xml",
+  });
   Ok(response)
 }
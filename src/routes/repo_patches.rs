@@ -0,0 +1,163 @@
+use crate::{
+  mail::{self, Email, Series},
+  route_prelude::*,
+};
+
+#[derive(Template)]
+#[template(path = "patches.html")]
+struct RepoPatchesTemplate<'a> {
+  repo: &'a Repository,
+  /// every series with its other versions
+  groups: Vec<Vec<Series<'a>>>,
+}
+
+/// A patch of a series, ready to be shown.
+struct RenderedPatch<'a> {
+  email: &'a Email,
+  number: usize,
+  message: &'a str,
+  /// the highlighted diff, or why it is not shown
+  diff_html: String,
+  /// the commit the patch became
+  applied: Option<Commit<'a>>,
+}
+
+/// Whether a series can be applied to the current HEAD.
+enum ApplyStatus<'a> {
+  Applies(Commit<'a>),
+  Fails {
+    number: usize,
+    error: String,
+  },
+  /// the check itself failed
+  Error(String),
+  Incomplete,
+  EmptyRepository,
+}
+
+#[derive(Template)]
+#[template(path = "series.html")]
+struct RepoSeriesTemplate<'a> {
+  repo: &'a Repository,
+  series: &'a Series<'a>,
+  versions: &'a [Series<'a>],
+  patches: Vec<RenderedPatch<'a>>,
+  status: ApplyStatus<'a>,
+}
+
+/// Apply the patches one after another to the tree of HEAD.
+///
+/// The trees in between are written to an in-memory object database, so
+/// nothing is added to the repository.
+fn check_series<'a>(
+  repo: &'a Repository,
+  diffs: &[(usize, Result<Diff, git2::Error>)],
+) -> ApplyStatus<'a> {
+  let head = match repo.head().and_then(|head| head.peel_to_commit()) {
+    Ok(head) => head,
+    Err(_) => return ApplyStatus::EmptyRepository,
+  };
+
+  // the number of the first patch that does not apply and why
+  let result = (|| -> Result<Option<(usize, String)>, git2::Error> {
+    repo.odb()?.add_new_mempack_backend(1000)?;
+    let mut tree = head.tree()?;
+    for (number, diff) in diffs {
+      let applied = match diff {
+        Ok(diff) => repo.apply_to_tree(&tree, diff, None),
+        Err(e) => Err(git2::Error::from_str(e.message())),
+      };
+      match applied {
+        Ok(mut index) => tree = repo.find_tree(index.write_tree_to(repo)?)?,
+        Err(e) => return Ok(Some((*number, e.message().to_string()))),
+      }
+    }
+    Ok(None)
+  })();
+
+  match result {
+    Ok(None) => ApplyStatus::Applies(head),
+    Ok(Some((number, error))) => ApplyStatus::Fails { number, error },
+    Err(e) => ApplyStatus::Error(e.message().to_string()),
+  }
+}
+
+pub(crate) async fn repo_patches(req: Request<()>) -> tide::Result {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let threads = super::mailing_list(&repo)?;
+
+  let tmpl = RepoPatchesTemplate {
+    repo: &repo,
+    groups: mail::series(&threads),
+  };
+  Ok(tmpl.into())
+}
+
+pub(crate) async fn repo_series(req: Request<()>) -> tide::Result {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  let threads = super::mailing_list(&repo)?;
+  let id = percent_encoding::percent_decode_str(req.param("message_id")?).decode_utf8_lossy();
+
+  let groups = mail::series(&threads);
+  let (versions, series) = groups
+    .iter()
+    .find_map(|group| {
+      let series = group.iter().find(|series| {
+        series.first().id == id || series.patches.iter().any(|patch| patch.id == id)
+      })?;
+      Some((group, series))
+    })
+    .ok_or_else(|| tide::Error::from_str(404, "this patch series does not exist."))?;
+
+  let syntax = SYNTAXES
+    .find_syntax_by_name("Diff")
+    .expect("diff syntax missing");
+  let diffs = series
+    .patches
+    .iter()
+    .map(|email| {
+      let (_, diff) = email.split_patch();
+      (
+        email.patch().unwrap().number,
+        Diff::from_buffer(diff.as_bytes()),
+      )
+    })
+    .collect::<Vec<_>>();
+  let mut applied = super::applied(&repo, series.patches.iter().copied());
+  let patches = series
+    .patches
+    .iter()
+    .zip(&diffs)
+    .map(|(email, (number, diff))| {
+      let diff_html = match diff {
+        Ok(diff) => match super::patch_text(diff, config().max_diff_size) {
+          Some(patch) => HighlightedLines::new(patch, syntax).collect(),
+          None => "This diff is too large to be displayed.".into(),
+        },
+        Err(_) => "This patch could not be read.".into(),
+      };
+      RenderedPatch {
+        email,
+        number: *number,
+        message: email.split_patch().0,
+        diff_html,
+        applied: applied.remove(email.id.as_str()),
+      }
+    })
+    .collect();
+
+  let status = if series.is_complete() {
+    check_series(&repo, &diffs)
+  } else {
+    ApplyStatus::Incomplete
+  };
+
+  let tmpl = RepoSeriesTemplate {
+    repo: &repo,
+    series,
+    versions,
+    patches,
+    status,
+  };
+  Ok(tmpl.into())
+}
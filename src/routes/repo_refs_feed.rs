@@ -8,16 +8,17 @@ struct RepoRefFeedTemplate<'a> {
   base_url: &'a str,
 }
 
-pub(crate) async fn repo_refs_feed(req: Request<()>) -> tide::Result {
-  let repo = repo_from_request(req.param("repo_name")?)?;
-  if repo.is_empty().unwrap() {
-    // show a server error
-    return Err(tide::Error::from_str(
-      503,
-      "Cannot show feed because there is nothing here.",
-    ));
-  }
+#[derive(Template)]
+#[template(path = "refs.atom", escape = "xml")]
+struct RepoRefAtomTemplate<'a> {
+  repo: &'a Repository,
+  tags: Vec<(String, String, Signature<'static>, String)>,
+  base_url: &'a str,
+}
 
+/// List the tags of a repository with the link to their page, their name,
+/// who created them and their message, newest first.
+pub(crate) fn tags(repo: &Repository) -> Vec<(String, String, Signature<'static>, String)> {
   let mut tags = Vec::new();
   repo
     .tag_foreach(|oid, name_bytes| {
@@ -54,22 +55,53 @@ pub(crate) async fn repo_refs_feed(req: Request<()>) -> tide::Result {
     .unwrap();
   // sort so that newest tags are at the top
   tags.sort_unstable_by(|(_, _, a, _), (_, _, b, _)| a.when().cmp(&b.when()).reverse());
+  tags
+}
 
-  let mut url = req.url().clone();
-  {
-    let mut segments = url.path_segments_mut().unwrap();
-    segments.pop(); // pop "log.xml" or "feed.xml"
-    if req.param("ref").is_ok() {
-      segments.pop(); // pop ref
-      segments.pop(); // pop "log/"
-    }
+enum FeedFormat {
+  Rss,
+  Atom,
+}
+
+pub(crate) async fn repo_refs_feed(req: Request<()>) -> tide::Result {
+  refs_feed(req, FeedFormat::Rss)
+}
+
+pub(crate) async fn repo_refs_atom(req: Request<()>) -> tide::Result {
+  refs_feed(req, FeedFormat::Atom)
+}
+
+fn refs_feed(req: Request<()>, format: FeedFormat) -> tide::Result {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  if repo.is_empty().unwrap() {
+    // show a server error
+    return Err(tide::Error::from_str(
+      503,
+      "Cannot show feed because there is nothing here.",
+    ));
   }
 
-  let tmpl = RepoRefFeedTemplate {
-    repo: &repo,
-    tags,
-    base_url: url.as_str(),
-  };
+  let tags = tags(&repo);
 
-  Ok(tmpl.into())
+  let base_url = format!("{}/{}", crate::base_url(&req), req.param("repo_name")?);
+
+  let mut response: tide::Response = match format {
+    FeedFormat::Rss => RepoRefFeedTemplate {
+      repo: &repo,
+      tags,
+      base_url: &base_url,
+    }
+    .into(),
+    FeedFormat::Atom => RepoRefAtomTemplate {
+      repo: &repo,
+      tags,
+      base_url: &base_url,
+    }
+    .into(),
+  };
+  response.set_content_type(match format {
+    FeedFormat::Rss => "application/rss+xml",
+    FeedFormat::Atom => "application/atom+xml",
+  });
+  Ok(response)
 }
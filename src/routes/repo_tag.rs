@@ -1,23 +1,35 @@
-use crate::route_prelude::*;
+use crate::{route_prelude::*, verify::Verification};
 
 #[derive(Template)]
 #[template(path = "tag.html")]
 struct RepoTagTemplate<'a> {
   repo: &'a Repository,
   tag: Tag<'a>,
+  verification: Option<Verification>,
 }
 
 pub(crate) async fn repo_tag(req: Request<()>) -> tide::Result {
-  let repo = repo_from_request(req.param("repo_name")?)?;
-  let tag = repo.revparse_single(req.param("tag")?)?.peel_to_tag();
+  // the repository can't be kept while the signature is verified
+  let (git_dir, tag) = {
+    let repo = repo_from_request(req.param("repo_name")?)?;
+    let tag = repo.revparse_single(req.param("tag")?)?.peel_to_tag();
+    (repo.path().to_path_buf(), tag.map(|tag| tag.id()))
+  };
 
-  if let Ok(tag) = tag {
-    let tmpl = RepoTagTemplate { repo: &repo, tag };
+  if let Ok(oid) = tag {
+    let verification = crate::verify::tag(git_dir, oid).await;
+    let repo = repo_from_request(req.param("repo_name")?)?;
+    let tmpl = RepoTagTemplate {
+      repo: &repo,
+      tag: repo.find_tag(oid)?,
+      verification,
+    };
     Ok(tmpl.into())
   } else {
     Ok(
       tide::Redirect::permanent(format!(
-        "/{}/commit/{}",
+        "{}/{}/commit/{}",
+        config().base_path,
         req.param("repo_name")?,
         req.param("tag")?
       ))
@@ -0,0 +1,32 @@
+use crate::{route_prelude::*, webhooks::Delivery};
+
+#[derive(Template)]
+#[template(path = "webhooks.html")]
+struct RepoWebhooksTemplate<'a> {
+  repo: &'a Repository,
+  deliveries: Vec<Delivery>,
+}
+
+pub(crate) async fn repo_webhooks(req: Request<()>) -> tide::Result {
+  let repo = repo_from_request(req.param("repo_name")?)?;
+  if crate::webhooks::urls(&repo).is_empty() {
+    return Err(tide::Error::from_str(
+      404,
+      "this repository has no webhooks.",
+    ));
+  }
+  let deliveries = crate::webhooks::deliveries(&repo).map_err(|e| {
+    tide::log::warn!(
+      "can't read the webhook deliveries of {:?}: {}",
+      repo.path(),
+      e
+    );
+    tide::Error::from_str(500, "the webhook deliveries could not be read.")
+  })?;
+
+  let tmpl = RepoWebhooksTemplate {
+    repo: &repo,
+    deliveries,
+  };
+  Ok(tmpl.into())
+}
@@ -0,0 +1,121 @@
+use async_std::{
+  io::{self, BufRead, Read},
+  task,
+};
+use std::{
+  pin::Pin,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+  },
+  task::{Context, Poll},
+  time::{Duration, Instant},
+};
+use tide::{Body, Middleware, Next, Request};
+
+/// The number of requests whose responses have not been sent completely
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a request as in flight for as long as it exists.
+struct InFlightGuard;
+
+impl InFlightGuard {
+  fn new() -> Self {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    Self
+  }
+}
+
+impl Drop for InFlightGuard {
+  fn drop(&mut self) {
+    IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+/// A response body that keeps its request in flight until it was read
+/// completely or the connection was closed.
+struct TrackedBody {
+  body: Body,
+  _guard: InFlightGuard,
+}
+
+impl Read for TrackedBody {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.body).poll_read(cx, buf)
+  }
+}
+
+impl BufRead for TrackedBody {
+  fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+    Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+  }
+
+  fn consume(mut self: Pin<&mut Self>, amt: usize) {
+    Pin::new(&mut self.body).consume(amt)
+  }
+}
+
+/// Keeps track of the requests that are being answered, including responses
+/// that are still being streamed, like large files during a clone.
+pub(crate) struct InFlight;
+
+#[async_trait::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for InFlight {
+  async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+    let guard = InFlightGuard::new();
+    let mut response = next.run(req).await;
+
+    let body = response.take_body();
+    let (len, mime) = (body.len(), body.mime().clone());
+    let mut tracked = Body::from_reader(
+      TrackedBody {
+        body,
+        _guard: guard,
+      },
+      len,
+    );
+    tracked.set_mime(mime);
+    response.set_body(tracked);
+    Ok(response)
+  }
+}
+
+/// Wait until the process is asked to stop with SIGTERM or SIGINT.
+///
+/// Receiving a second signal stops the process immediately.
+pub(crate) async fn signal() -> io::Result<()> {
+  use signal_hook::{consts::*, flag};
+
+  let stop = Arc::new(AtomicBool::new(false));
+  for signal in [SIGTERM, SIGINT] {
+    // exits if `stop` is already set
+    flag::register_conditional_shutdown(signal, 1, stop.clone())?;
+    flag::register(signal, stop.clone())?;
+  }
+
+  while !stop.load(Ordering::Relaxed) {
+    task::sleep(Duration::from_millis(100)).await;
+  }
+  Ok(())
+}
+
+/// Wait for the requests in flight to finish, but not longer than `timeout`.
+pub(crate) async fn drain(timeout: Duration) {
+  let start = Instant::now();
+  let mut remaining = IN_FLIGHT.load(Ordering::SeqCst);
+  if remaining > 0 {
+    println!("waiting for {} requests to finish", remaining);
+  }
+  while remaining > 0 {
+    if start.elapsed() >= timeout {
+      tide::log::warn!("stopping with {} requests still running", remaining);
+      return;
+    }
+    task::sleep(Duration::from_millis(100)).await;
+    remaining = IN_FLIGHT.load(Ordering::SeqCst);
+  }
+}
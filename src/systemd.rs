@@ -0,0 +1,68 @@
+//! Integration with the service manager, following the protocols described in
+//! `sd_listen_fds(3)` and `sd_notify(3)`, without linking to libsystemd.
+
+use std::{env, os::unix::io::RawFd, sync::Mutex};
+
+/// The first file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The sockets found by `take_listen_fds`
+static LISTEN_FDS: Mutex<Vec<(RawFd, String)>> = Mutex::new(Vec::new());
+
+/// Take the sockets passed by socket activation, with the names given to them
+/// by `FileDescriptorName=` in the socket unit, for `listen_fds`.
+///
+/// The environment variables are removed so they are not inherited by child
+/// processes like git. Changing the environment while other threads might read
+/// it is not safe, so this has to be called before any threads are started.
+pub(crate) fn take_listen_fds() {
+  *LISTEN_FDS.lock().unwrap() = read_listen_fds();
+}
+
+/// The sockets taken by `take_listen_fds`, which can only be used once.
+pub(crate) fn listen_fds() -> Vec<(RawFd, String)> {
+  std::mem::take(&mut LISTEN_FDS.lock().unwrap())
+}
+
+fn read_listen_fds() -> Vec<(RawFd, String)> {
+  let pid = env::var("LISTEN_PID").ok();
+  let fds = env::var("LISTEN_FDS").ok();
+  let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+  env::remove_var("LISTEN_PID");
+  env::remove_var("LISTEN_FDS");
+  env::remove_var("LISTEN_FDNAMES");
+
+  // the variables may have been meant for a parent process
+  if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+    return Vec::new();
+  }
+  let count = fds.and_then(|fds| fds.parse().ok()).unwrap_or(0);
+  let mut names = names.split(':');
+  (LISTEN_FDS_START..LISTEN_FDS_START + count)
+    .map(|fd| (fd, names.next().unwrap_or_default().to_string()))
+    .collect()
+}
+
+/// Tell the service manager about a change of state, like `READY=1`. Does
+/// nothing if agit was not started by a service manager that wants to know.
+pub(crate) fn notify(state: &str) {
+  use std::os::unix::net::UnixDatagram;
+
+  let path = match env::var_os("NOTIFY_SOCKET") {
+    Some(path) => path,
+    None => return,
+  };
+  let result = UnixDatagram::unbound().and_then(|socket| {
+    // sockets in the abstract namespace start with @
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+      use std::os::linux::net::SocketAddrExt;
+      let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+      return socket.send_to_addr(state.as_bytes(), &addr);
+    }
+    socket.send_to(state.as_bytes(), &path)
+  });
+  if let Err(e) = result {
+    tide::log::warn!("could not notify the service manager: {}", e);
+  }
+}
@@ -0,0 +1,405 @@
+//! Verifying the GPG and SSH signatures of commits and tags, with `gpg` and
+//! `ssh-keygen`.
+
+use crate::config;
+use git2::{Oid, Repository};
+use std::{
+  collections::{HashMap, VecDeque},
+  fs,
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+  process::{Command, Output, Stdio},
+  sync::{
+    mpsc::{self, SyncSender},
+    Mutex,
+  },
+  thread,
+  time::{Duration, Instant, SystemTime},
+};
+use tempfile::NamedTempFile;
+
+const PGP_SIGNATURE: &[u8] = b"-----BEGIN PGP SIGNATURE-----";
+const SSH_SIGNATURE: &[u8] = b"-----BEGIN SSH SIGNATURE-----";
+/// How long `gpg` and `ssh-keygen` may take before they are killed
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The number of commits that can wait to be verified in the background
+const QUEUE_SIZE: usize = 1000;
+/// The number of verification results that are kept
+const CACHE_SIZE: usize = 10_000;
+/// How often the keyring and allowed signers files are checked for changes
+const KEYS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether a signature could be verified.
+#[derive(Clone)]
+pub(crate) enum Verification {
+  /// the signature is good and made by a known key, described by the string
+  Verified(String),
+  /// the signature does not match or its key is expired or revoked, for the
+  /// reason in the string
+  Unverified(String),
+  /// the signature might be good, but its key is not in the keyring or allowed
+  /// signers file
+  UnknownKey(String),
+}
+
+impl Verification {
+  /// The CSS class of the badge.
+  pub(crate) fn class(&self) -> &'static str {
+    match self {
+      Self::Verified(_) => "verified",
+      Self::Unverified(_) => "unverified",
+      Self::UnknownKey(_) => "unknown-key",
+    }
+  }
+
+  pub(crate) fn label(&self) -> &'static str {
+    match self {
+      Self::Verified(_) => "verified",
+      Self::Unverified(_) => "unverified",
+      Self::UnknownKey(_) => "unknown key",
+    }
+  }
+
+  pub(crate) fn description(&self) -> String {
+    match self {
+      Self::Verified(signer) => format!("signed by {}", signer),
+      Self::Unverified(reason) => reason.clone(),
+      Self::UnknownKey(key) => format!("signed with the unknown key {}", key),
+    }
+  }
+}
+
+/// The verification results of signed objects, which only change when the
+/// trusted keys change.
+#[derive(Default)]
+struct Cache {
+  /// the keyring and allowed signers files with their modification times
+  keys: Vec<(PathBuf, Option<SystemTime>)>,
+  /// when `keys` were last checked
+  checked: Option<Instant>,
+  /// `None` for objects that aren't signed
+  results: HashMap<Oid, Option<Verification>>,
+  /// the objects in `results` from the oldest to the newest, so the oldest can
+  /// be forgotten when there are more than `CACHE_SIZE`
+  order: VecDeque<Oid>,
+}
+
+impl Cache {
+  fn insert(&mut self, oid: Oid, verification: Option<Verification>) {
+    if self.results.insert(oid, verification).is_none() {
+      self.order.push_back(oid);
+      if self.order.len() > CACHE_SIZE {
+        let oldest = self.order.pop_front().unwrap();
+        self.results.remove(&oldest);
+      }
+    }
+  }
+}
+
+lazy_static::lazy_static! {
+  static ref CACHE: Mutex<Cache> = Mutex::default();
+  /// The commits that are verified in the background, with their git
+  /// directories.
+  static ref QUEUE: SyncSender<(PathBuf, Oid)> = {
+    let (sender, receiver) = mpsc::sync_channel::<(PathBuf, Oid)>(QUEUE_SIZE);
+    thread::spawn(move || {
+      for (git_dir, oid) in receiver {
+        if let Ok(repo) = Repository::open(git_dir) {
+          verify_commit(&repo, oid);
+        }
+      }
+    });
+    sender
+  };
+}
+
+/// Verify the signature of the commit `oid` in the repository at `git_dir`, or
+/// `None` if it isn't signed.
+pub(crate) async fn commit(git_dir: PathBuf, oid: Oid) -> Option<Verification> {
+  async_std::task::spawn_blocking(move || verify_commit(&Repository::open(git_dir).ok()?, oid))
+    .await
+}
+
+/// Verify the signature of the annotated tag `oid` in the repository at
+/// `git_dir`, or `None` if it isn't signed.
+pub(crate) async fn tag(git_dir: PathBuf, oid: Oid) -> Option<Verification> {
+  async_std::task::spawn_blocking(move || verify_tag(&Repository::open(git_dir).ok()?, oid)).await
+}
+
+/// The verification of the commit `oid` if it was verified before, for lists
+/// of commits which should not wait for `gpg` or `ssh-keygen`. Other commits
+/// are verified in the background.
+pub(crate) fn cached_commit(repo: &Repository, oid: Oid) -> Option<Verification> {
+  match lookup(oid) {
+    Some(verification) => verification,
+    None => {
+      // if the queue is full, the commit is queued again the next time
+      let _ = QUEUE.try_send((repo.path().to_path_buf(), oid));
+      None
+    }
+  }
+}
+
+fn verify_commit(repo: &Repository, oid: Oid) -> Option<Verification> {
+  cached(oid, || {
+    let (signature, data) = repo.extract_signature(&oid, None).ok()?;
+    Some(verify(&signature, &data))
+  })
+}
+
+fn verify_tag(repo: &Repository, oid: Oid) -> Option<Verification> {
+  cached(oid, || {
+    let odb = repo.odb().ok()?;
+    let object = odb.read(oid).ok()?;
+    // the signature is appended to the tag message
+    let data = object.data();
+    let start = [PGP_SIGNATURE, SSH_SIGNATURE]
+      .iter()
+      .filter_map(|marker| {
+        data
+          .windows(marker.len() + 1)
+          .rposition(|window| window[0] == b'\n' && &window[1..] == *marker)
+      })
+      .max()?
+      + 1;
+    Some(verify(&data[start..], &data[..start]))
+  })
+}
+
+/// The cached result for `oid`, or `None` if it has not been verified since
+/// the keys last changed.
+fn lookup(oid: Oid) -> Option<Option<Verification>> {
+  let mut cache = CACHE.lock().unwrap();
+  // pages look up many commits, so the keys are not checked for every one
+  if cache
+    .checked
+    .is_none_or(|checked| checked.elapsed() >= KEYS_CHECK_INTERVAL)
+  {
+    let config = config();
+    let keys = [&config.gpg_keyring, &config.ssh_allowed_signers]
+      .into_iter()
+      .filter(|path| !path.is_empty())
+      .map(|path| {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        (PathBuf::from(path), modified)
+      })
+      .collect::<Vec<_>>();
+    if cache.keys != keys {
+      *cache = Cache {
+        keys,
+        ..Cache::default()
+      };
+    }
+    cache.checked = Some(Instant::now());
+  }
+  cache.results.get(&oid).cloned()
+}
+
+/// `verify` returns an error if the signature could not be checked, like when
+/// `gpg` timed out. Such results are shown as unverified, but not cached.
+fn cached(
+  oid: Oid,
+  verify: impl FnOnce() -> Option<Result<Verification, String>>,
+) -> Option<Verification> {
+  if let Some(verification) = lookup(oid) {
+    return verification;
+  }
+  // verifying takes a while, so other requests should not wait for it
+  let verification = match verify() {
+    Some(Ok(verification)) => Some(verification),
+    Some(Err(e)) => return Some(Verification::Unverified(e)),
+    None => None,
+  };
+  CACHE.lock().unwrap().insert(oid, verification.clone());
+  verification
+}
+
+fn verify(signature: &[u8], data: &[u8]) -> Result<Verification, String> {
+  let file = signature_file(signature).map_err(|e| format!("could not be checked: {}", e))?;
+  if signature.starts_with(PGP_SIGNATURE) {
+    verify_gpg(file.path(), data)
+  } else if signature.starts_with(SSH_SIGNATURE) {
+    verify_ssh(file.path(), data)
+  } else {
+    Ok(Verification::Unverified(
+      "the signature format is not supported".into(),
+    ))
+  }
+}
+
+/// A temporary file with a signature, because that is how `gpg` and
+/// `ssh-keygen` want it. It has a random name and is only readable by agit, and
+/// it is deleted when it is dropped.
+fn signature_file(signature: &[u8]) -> io::Result<NamedTempFile> {
+  let mut file = tempfile::Builder::new()
+    .prefix("agit-signature-")
+    .tempfile()?;
+  file.write_all(signature)?;
+  Ok(file)
+}
+
+/// Run `command` with `data` as its standard input, killing it if it takes
+/// longer than `TIMEOUT`.
+fn run(command: &mut Command, data: &[u8]) -> io::Result<Output> {
+  let mut child = command
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()?;
+  // written in another thread, so a command that doesn't read its input can
+  // still be killed
+  let mut stdin = child.stdin.take().unwrap();
+  let data = data.to_vec();
+  // the command might exit without reading everything
+  thread::spawn(move || stdin.write_all(&data));
+
+  // the output is small, so it fits into the pipes until the command exits
+  let deadline = Instant::now() + TIMEOUT;
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if Instant::now() > deadline {
+      let _ = child.kill();
+      let _ = child.wait();
+      return Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("timed out after {} seconds", TIMEOUT.as_secs()),
+      ));
+    }
+    thread::sleep(Duration::from_millis(10));
+  };
+  let mut output = Output {
+    status,
+    stdout: Vec::new(),
+    stderr: Vec::new(),
+  };
+  child
+    .stdout
+    .take()
+    .unwrap()
+    .read_to_end(&mut output.stdout)?;
+  child
+    .stderr
+    .take()
+    .unwrap()
+    .read_to_end(&mut output.stderr)?;
+  Ok(output)
+}
+
+fn verify_gpg(signature: &Path, data: &[u8]) -> Result<Verification, String> {
+  let config = config();
+  let mut command = Command::new("gpg");
+  command.args(["--batch", "--no-tty", "--status-fd=1"]);
+  if !config.gpg_keyring.is_empty() {
+    // gpg looks for relative paths in its home directory
+    let keyring =
+      fs::canonicalize(&config.gpg_keyring).unwrap_or_else(|_| PathBuf::from(&config.gpg_keyring));
+    command
+      .arg("--no-default-keyring")
+      .arg("--keyring")
+      .arg(keyring);
+  }
+  command.arg("--verify").arg(signature).arg("-");
+  let output = run(&mut command, data).map_err(|e| format!("could not run gpg: {}", e))?;
+
+  // see doc/DETAILS in the GnuPG source for the status lines
+  let status = String::from_utf8_lossy(&output.stdout);
+  let mut missing_key = None;
+  for line in status.lines() {
+    let mut words = line.splitn(4, ' ').skip(1);
+    match (words.next(), words.next(), words.next()) {
+      (Some("GOODSIG"), Some(_), Some(user)) => {
+        return Ok(Verification::Verified(user.to_string()))
+      }
+      (Some("BADSIG"), _, _) => return Ok(Verification::Unverified("the signature is bad".into())),
+      (Some("EXPSIG"), _, _) => {
+        return Ok(Verification::Unverified("the signature is expired".into()))
+      }
+      (Some("EXPKEYSIG"), _, _) => {
+        return Ok(Verification::Unverified(
+          "the key of the signature is expired".into(),
+        ))
+      }
+      (Some("REVKEYSIG"), _, _) => {
+        return Ok(Verification::Unverified(
+          "the key of the signature was revoked".into(),
+        ))
+      }
+      (Some("NO_PUBKEY"), Some(key), _) => missing_key = Some(key.to_string()),
+      _ => {}
+    }
+  }
+  Ok(match missing_key {
+    Some(key) => Verification::UnknownKey(key),
+    None => Verification::Unverified("gpg could not check the signature".into()),
+  })
+}
+
+fn verify_ssh(signature: &Path, data: &[u8]) -> Result<Verification, String> {
+  let config = config();
+  let allowed_signers = &config.ssh_allowed_signers;
+
+  // which of the allowed signers has the key of the signature
+  let principal = if allowed_signers.is_empty() {
+    None
+  } else {
+    let output = run(
+      Command::new("ssh-keygen")
+        .args(["-Y", "find-principals", "-f"])
+        .arg(allowed_signers)
+        .arg("-s")
+        .arg(signature),
+      &[],
+    )
+    .map_err(|e| format!("could not run ssh-keygen: {}", e))?;
+    Some(output)
+      .filter(|output| output.status.success())
+      .and_then(|output| {
+        let principals = String::from_utf8_lossy(&output.stdout);
+        principals.lines().next().map(String::from)
+      })
+  };
+
+  match principal {
+    Some(principal) => {
+      let verified = run(
+        Command::new("ssh-keygen")
+          .args(["-Y", "verify", "-n", "git", "-f"])
+          .arg(allowed_signers)
+          .arg("-I")
+          .arg(&principal)
+          .arg("-s")
+          .arg(signature),
+        data,
+      );
+      match verified {
+        Ok(output) if output.status.success() => Ok(Verification::Verified(principal)),
+        Ok(_) => Ok(Verification::Unverified("the signature is bad".into())),
+        Err(e) => Err(format!("could not run ssh-keygen: {}", e)),
+      }
+    }
+    // the signature can still be checked, just not whose key it is
+    None => {
+      let checked = run(
+        Command::new("ssh-keygen")
+          .args(["-Y", "check-novalidate", "-n", "git", "-s"])
+          .arg(signature),
+        data,
+      );
+      match checked {
+        Ok(output) if output.status.success() => {
+          // like: Good "git" signature with ED25519 key SHA256:...
+          let stdout = String::from_utf8_lossy(&output.stdout);
+          let key = stdout
+            .split_once(" key ")
+            .map(|(_, key)| key.trim().to_string())
+            .unwrap_or_default();
+          Ok(Verification::UnknownKey(key))
+        }
+        Ok(_) => Ok(Verification::Unverified("the signature is bad".into())),
+        Err(e) => Err(format!("could not run ssh-keygen: {}", e)),
+      }
+    }
+  }
+}
@@ -0,0 +1,318 @@
+//! Watching the repositories for changed refs, so other parts of agit can react
+//! to pushes instead of checking every repository again and again.
+//!
+//! On Linux, the `refs` directories and `packed-refs` files are watched with
+//! inotify. All repositories are also scanned regularly, which finds new
+//! repositories and is the only way changes are noticed on other systems.
+
+use crate::refs::{self, RefSnapshot};
+use git2::Repository;
+use std::{
+  collections::{HashMap, HashSet},
+  path::PathBuf,
+  sync::{
+    mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    Arc, Mutex,
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+/// How often all repositories are scanned if changes can't be watched
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// How often all repositories are scanned while changes are watched, to find
+/// new repositories
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait for more changes after a change, since a push changes
+/// several files
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// The refs of a repository changed.
+pub(crate) struct RefChange {
+  /// the git directory of the repository
+  pub(crate) path: PathBuf,
+  /// the refs before the change, empty for a repository that was just added
+  pub(crate) old: RefSnapshot,
+  /// the refs after the change
+  pub(crate) new: RefSnapshot,
+}
+
+lazy_static::lazy_static! {
+  static ref SUBSCRIBERS: Mutex<Vec<Sender<Arc<RefChange>>>> = Mutex::default();
+}
+
+/// Receive the changes of all repositories from now on.
+pub(crate) fn subscribe() -> Receiver<Arc<RefChange>> {
+  let (sender, receiver) = mpsc::channel();
+  SUBSCRIBERS.lock().unwrap().push(sender);
+  receiver
+}
+
+fn publish(change: RefChange) {
+  let change = Arc::new(change);
+  SUBSCRIBERS
+    .lock()
+    .unwrap()
+    // forget the subscribers that went away
+    .retain(|sender| sender.send(change.clone()).is_ok());
+}
+
+/// What the inotify thread tells the watcher.
+enum Event {
+  /// the refs of the repository with this git directory might have changed
+  Changed(PathBuf),
+  /// events were lost, so every repository has to be checked
+  Overflow,
+}
+
+/// Watch the exported repositories in the background.
+pub(crate) fn start() {
+  let (sender, receiver) = mpsc::channel();
+  let watcher = watcher::Watcher::start(sender)
+    .map_err(|e| tide::log::warn!("can't watch repositories, checking regularly: {}", e))
+    .ok();
+
+  thread::spawn(move || {
+    let mut snapshots = HashMap::<PathBuf, RefSnapshot>::new();
+    let mut initial = true;
+    let mut next_scan = Instant::now();
+    loop {
+      let mut changed = HashSet::new();
+      let mut scan = false;
+      match &watcher {
+        Some(_) => {
+          let timeout = next_scan.saturating_duration_since(Instant::now());
+          let mut event = receiver.recv_timeout(timeout);
+          loop {
+            match event {
+              Ok(Event::Changed(path)) => {
+                changed.insert(path);
+              }
+              Ok(Event::Overflow) | Err(RecvTimeoutError::Timeout) => scan = true,
+              Err(RecvTimeoutError::Disconnected) => {
+                // watching stopped working, so check all the time
+                scan = true;
+                next_scan = Instant::now() + POLL_INTERVAL;
+                thread::sleep(POLL_INTERVAL);
+                break;
+              }
+            }
+            event = receiver.recv_timeout(SETTLE_TIME);
+            if let Err(RecvTimeoutError::Timeout) = event {
+              break;
+            }
+          }
+        }
+        None => {
+          thread::sleep(next_scan.saturating_duration_since(Instant::now()));
+          scan = true;
+        }
+      }
+
+      if !scan && Instant::now() < next_scan {
+        // events can still arrive for repositories that are no longer exported
+        changed.retain(|path| snapshots.contains_key(path));
+      } else {
+        let repos = crate::exported_repos()
+          .iter()
+          .map(|repo| repo.path().to_path_buf())
+          .collect::<HashSet<_>>();
+        snapshots.retain(|path, _| repos.contains(path));
+        for path in &repos {
+          if let Some(watcher) = &watcher {
+            if !snapshots.contains_key(path) {
+              watcher.add(path);
+            }
+          }
+        }
+        changed = repos;
+        next_scan = Instant::now()
+          + if watcher.is_some() {
+            RESCAN_INTERVAL
+          } else {
+            POLL_INTERVAL
+          };
+      }
+
+      for path in changed {
+        let new = match Repository::open(&path).and_then(|repo| refs::snapshot(&repo)) {
+          Ok(new) => new,
+          Err(e) => {
+            tide::log::warn!("can't read the refs of {:?}: {}", path, e);
+            continue;
+          }
+        };
+        let updated = match snapshots.get(&path) {
+          Some(old) => *old != new,
+          // the first scan only finds out what the refs are, but a repository
+          // that was added later had no refs before
+          None => !initial && !new.is_empty(),
+        };
+        let old = snapshots
+          .insert(path.clone(), new.clone())
+          .unwrap_or_default();
+        if updated {
+          publish(RefChange { path, old, new });
+        }
+      }
+      initial = false;
+    }
+  });
+}
+
+#[cfg(target_os = "linux")]
+mod watcher {
+  use super::Event;
+  use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+  use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+  };
+
+  /// The changes that git makes when refs are updated: lock files are renamed
+  /// over the refs and deleted refs are removed.
+  const MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::ONLYDIR);
+
+  /// The watched directories by their watch descriptor, with the git directory
+  /// they belong to.
+  type Directories = Arc<Mutex<HashMap<WatchDescriptor, (PathBuf, PathBuf)>>>;
+
+  pub(super) struct Watcher {
+    watches: Mutex<Watches>,
+    directories: Directories,
+  }
+
+  impl Watcher {
+    /// Start reading inotify events in a thread that sends them to `sender`.
+    pub(super) fn start(sender: Sender<Event>) -> io::Result<Self> {
+      let mut inotify = Inotify::init()?;
+      let watcher = Self {
+        watches: Mutex::new(inotify.watches()),
+        directories: Directories::default(),
+      };
+      let mut watches = inotify.watches();
+      let directories = watcher.directories.clone();
+
+      thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+          let events = match inotify.read_events_blocking(&mut buffer) {
+            Ok(events) => events,
+            Err(e) => {
+              tide::log::warn!("can't watch repositories any more: {}", e);
+              // the watcher notices that the sender was dropped
+              return;
+            }
+          };
+          for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+              if sender.send(Event::Overflow).is_err() {
+                return;
+              }
+              continue;
+            }
+            let mut directories = directories.lock().unwrap();
+            if event.mask.contains(EventMask::IGNORED) {
+              directories.remove(&event.wd);
+              continue;
+            }
+            let (git_dir, directory) = match directories.get(&event.wd) {
+              Some(watched) => watched.clone(),
+              None => continue,
+            };
+            let name = match event.name.and_then(|name| name.to_str()) {
+              Some(name) => name,
+              None => continue,
+            };
+            if directory == git_dir {
+              // in the git directory itself, only packed-refs is interesting
+              if name != "packed-refs" {
+                continue;
+              }
+            } else if event.mask.contains(EventMask::ISDIR) {
+              // refs in a new directory like refs/heads/feature/
+              if event.mask.contains(EventMask::CREATE) {
+                add(
+                  &mut watches,
+                  &mut directories,
+                  &git_dir,
+                  &directory.join(name),
+                );
+              }
+            } else if name.ends_with(".lock") {
+              continue;
+            }
+            drop(directories);
+            if sender.send(Event::Changed(git_dir)).is_err() {
+              return;
+            }
+          }
+        }
+      });
+      Ok(watcher)
+    }
+
+    /// Watch the refs of the repository with the git directory `git_dir`.
+    pub(super) fn add(&self, git_dir: &Path) {
+      let mut watches = self.watches.lock().unwrap();
+      let mut directories = self.directories.lock().unwrap();
+      add(&mut watches, &mut directories, git_dir, git_dir);
+    }
+  }
+
+  /// Watch `directory` and, if it is not the git directory, its subdirectories.
+  fn add(
+    watches: &mut Watches,
+    directories: &mut HashMap<WatchDescriptor, (PathBuf, PathBuf)>,
+    git_dir: &Path,
+    directory: &Path,
+  ) {
+    match watches.add(directory, MASK) {
+      Ok(wd) => {
+        directories.insert(wd, (git_dir.to_path_buf(), directory.to_path_buf()));
+      }
+      Err(e) => {
+        tide::log::warn!("can't watch {:?}: {}", directory, e);
+        return;
+      }
+    }
+    let subdirectories = if directory == git_dir {
+      vec![git_dir.join("refs")]
+    } else {
+      std::fs::read_dir(directory)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_dir())
+        .collect()
+    };
+    for subdirectory in subdirectories {
+      add(watches, directories, git_dir, &subdirectory);
+    }
+  }
+}
+
+/// Changes are only noticed by scanning the repositories regularly.
+#[cfg(not(target_os = "linux"))]
+mod watcher {
+  use super::Event;
+  use std::{io, path::Path, sync::mpsc::Sender};
+
+  pub(super) struct Watcher;
+
+  impl Watcher {
+    pub(super) fn start(_sender: Sender<Event>) -> io::Result<Self> {
+      Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub(super) fn add(&self, _git_dir: &Path) {}
+  }
+}